@@ -3,34 +3,98 @@
 
 use sled;
 use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
 use std::sync::Mutex;
 
 
 use crate::error::Error;
 use crate::settings;
+use crate::chunker::Chunk;
+use crate::lexical::LexicalIndex;
 
 pub struct Catalog {
     db: Option<sled::Db>,
+    lexical: LexicalIndex,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Entry {
     pub filename: String,
-    indexes: Vec<u64>,
+    pub indexes: Vec<u64>,
+    // modification time in seconds since epoch, size in bytes and sha256 of
+    // the file content at the time it was indexed.  Entries written by older
+    // versions don't have these and are treated as changed on the next scan.
+    #[serde(default)]
+    pub mtime: u64,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub hash: String,
+    // number of chunks the document was embedded as
+    #[serde(default)]
+    pub chunks: usize,
+    // when the document was last embedded, in seconds since epoch, and its
+    // mime type as guessed from the name
+    #[serde(default)]
+    pub indexed_at: u64,
+    #[serde(default)]
+    pub mime: String,
+    // what the parser found out about the document, e.g. its title and author
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
 }
 
 impl Entry {
-    pub fn new(filename: String, indexes: Vec<u64>) -> Self {
-        Entry { filename, indexes }
+    pub fn new(filename: String, indexes: Vec<u64>, mtime: u64, size: u64, hash: String) -> Self {
+        let chunks = indexes.len();
+        let indexed_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mime = mime_guess::from_path(&filename).first_or_octet_stream().to_string();
+        Entry { filename, indexes, mtime, size, hash, chunks, indexed_at, mime, metadata: BTreeMap::new() }
     }
 }
 
+// True if `path` is `dir` or below it
+pub fn path_contains(dir: &str, path: &str) -> bool {
+    path == dir || path.starts_with(&format!("{}/", dir.trim_end_matches('/')))
+}
+
 impl Catalog {
     pub fn new() -> Self {
         let db_dir = settings::get_db_dir();
         log::info!("Opening catalog at {}", db_dir);
         let db = sled::open(db_dir).unwrap();
-        Catalog { db: Some(db) }
+        let lexical = LexicalIndex::new(db.open_tree("lexical").unwrap());
+        let catalog = Catalog { db: Some(db), lexical };
+        catalog.backfill_lexical();
+        catalog
+    }
+
+    // A catalog of its own for each test, deleted when it is dropped
+    #[cfg(test)]
+    pub fn temporary() -> Self {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let lexical = LexicalIndex::new(db.open_tree("lexical").unwrap());
+        Catalog { db: Some(db), lexical }
+    }
+
+    // Chunks stored before the lexical index existed are added to it once
+    fn backfill_lexical(&self) {
+        let db = self.db.as_ref().unwrap();
+        if db.open_tree("lexical").unwrap().len() > 0 {
+            return;
+        }
+        let mut n = 0;
+        for kv in db.scan_prefix("/bychunk/") {
+            let (key, value) = kv.unwrap();
+            let id = std::str::from_utf8(&key["/bychunk/".len()..]).unwrap().parse::<u64>().unwrap();
+            if let Ok(chunk) = serde_json::from_slice::<Chunk>(value.as_ref()) {
+                self.lexical.add(id, &chunk.text);
+                n += 1;
+            }
+        }
+        if n > 0 {
+            log::info!("Added {} chunks to the lexical index", n);
+        }
     }
 
     pub fn add(&self, entry: Entry) {
@@ -46,10 +110,15 @@ impl Catalog {
         log::debug!("key: {}, value: {}", key, value);
         db.insert(key, value.as_bytes()).unwrap();
 
-        for index in entry.indexes {
+        for index in entry.indexes.iter() {
             let key = std::fmt::format(format_args!("/byindex/{}", index));
             db.insert(key, value.as_bytes()).unwrap();
         }
+
+        if entry.hash.len() > 0 {
+            let key = std::fmt::format(format_args!("/byhash/{}", entry.hash));
+            db.insert(key, entry.filename.as_bytes()).unwrap();
+        }
     }
 
     pub fn get_by_file(&self, filename: String) -> Result<Entry, Error> {
@@ -60,6 +129,9 @@ impl Catalog {
         }
         let key = std::fmt::format(format_args!("/byfile/{}", filename2));
         let value = db.get(key).unwrap();
+        if value.is_none() {
+            return Err(Error::new("File not found"));
+        }
         let entry = serde_json::from_str(std::str::from_utf8(value.unwrap().as_ref()).unwrap()).unwrap();
         Ok(entry)
     }
@@ -76,18 +148,219 @@ impl Catalog {
     }
 
     pub fn delete(&self, filename: String) {
+        self.delete_keys(filename, true);
+    }
+
+    // Re-keys the entry of a moved file.  The vectors and their chunks are
+    // unchanged, so only the file keys are replaced.
+    pub fn rename(&self, from: String, entry: Entry) {
+        self.delete_keys(from, false);
+        self.add(entry);
+    }
+
+    fn delete_keys(&self, filename: String, with_chunks: bool) {
         let db = self.db.as_ref().unwrap();
         let mut filename2 = filename.clone();
         if filename2.starts_with("/") {
            filename2 = filename[1..].to_string();
         }
         let key = std::fmt::format(format_args!("/byfile/{}", filename2));
-        let entry = self.get_by_file(filename).unwrap();
+        let entry = match self.get_by_file(filename) {
+            Ok(entry) => entry,
+            Err(_) => return,
+        };
         db.remove(key).unwrap();
         for index in entry.indexes {
             let key = std::fmt::format(format_args!("/byindex/{}", index));
             db.remove(key).unwrap();
+            if with_chunks {
+                let key = std::fmt::format(format_args!("/bychunk/{}", index));
+                db.remove(key).unwrap();
+                self.lexical.remove(index);
+            }
+        }
+        // another file with the same content may own the hash key by now
+        if entry.hash.len() > 0 {
+            let key = std::fmt::format(format_args!("/byhash/{}", entry.hash));
+            if let Some(owner) = db.get(key.clone()).unwrap() {
+                if owner.as_ref() == entry.filename.as_bytes() {
+                    db.remove(key).unwrap();
+                }
+            }
+        }
+    }
+
+    pub fn add_chunks(&self, chunks: &Vec<(u64, Chunk)>) {
+        let db = self.db.as_ref().unwrap();
+        for (index, chunk) in chunks {
+            let key = std::fmt::format(format_args!("/bychunk/{}", index));
+            let value: String = serde_json::to_string(chunk).unwrap();
+            db.insert(key, value.as_bytes()).unwrap();
+            self.lexical.add(*index, &chunk.text);
+        }
+    }
+
+    // BM25 ranked chunk ids for the query
+    pub fn search_lexical(&self, query: &str, k: usize) -> Vec<(u64, f32)> {
+        self.lexical.search(query, k)
+    }
+
+    pub fn get_chunk(&self, index: u64) -> Result<Chunk, Error> {
+        let db = self.db.as_ref().unwrap();
+        let key = std::fmt::format(format_args!("/bychunk/{}", index));
+        let value = db.get(key).unwrap();
+        if value.is_none() {
+            return Err(Error::new("Chunk not found"));
+        }
+        let chunk = serde_json::from_str(std::str::from_utf8(value.unwrap().as_ref()).unwrap()).unwrap();
+        Ok(chunk)
+    }
+
+    pub fn get_by_hash(&self, hash: &str) -> Result<Entry, Error> {
+        let db = self.db.as_ref().unwrap();
+        let key = std::fmt::format(format_args!("/byhash/{}", hash));
+        let value = db.get(key).unwrap();
+        if value.is_none() {
+            return Err(Error::new("Hash not found"));
+        }
+        let filename = String::from_utf8_lossy(value.unwrap().as_ref()).to_string();
+        self.get_by_file(filename)
+    }
+
+    pub fn entries(&self) -> Vec<Entry> {
+        self.entries_below("")
+    }
+
+    // Entries of the files that are `prefix` or below it.  Keys are scanned
+    // by the raw prefix, which also takes in siblings like /a/bc of /a/b.
+    pub fn entries_below(&self, prefix: &str) -> Vec<Entry> {
+        let db = self.db.as_ref().unwrap();
+        let mut entries = Vec::new();
+        for kv in db.scan_prefix(format!("/byfile/{}", prefix.trim_start_matches('/'))) {
+            let (_, value) = kv.unwrap();
+            match serde_json::from_str::<Entry>(std::str::from_utf8(value.as_ref()).unwrap()) {
+                Ok(entry) if path_contains(prefix, &entry.filename) => entries.push(entry),
+                Ok(_) => {}
+                Err(e) => log::warn!("Skipping corrupt catalog entry: {}", e),
+            }
+        }
+        entries
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.db.as_ref().unwrap().scan_prefix("/byfile/").count()
+    }
+
+    // Files the crawler passed over are recorded with the reason, e.g.
+    // "too_large", and what went wrong as "reason: detail", until they are
+    // indexed or deleted
+    pub fn set_skipped(&self, filename: &str, reason: &str, detail: &str) {
+        let db = self.db.as_ref().unwrap();
+        let key = std::fmt::format(format_args!("/skipped/{}", filename.trim_start_matches('/')));
+        let value = if detail.len() > 0 { format!("{}: {}", reason, detail) } else { reason.to_string() };
+        db.insert(key, value.as_bytes()).unwrap();
+    }
+
+    pub fn get_skipped(&self, filename: &str) -> Option<String> {
+        let db = self.db.as_ref().unwrap();
+        let key = std::fmt::format(format_args!("/skipped/{}", filename.trim_start_matches('/')));
+        db.get(key).unwrap().map(|v| String::from_utf8_lossy(v.as_ref()).to_string())
+    }
+
+    pub fn clear_skipped(&self, filename: &str) {
+        let db = self.db.as_ref().unwrap();
+        let key = std::fmt::format(format_args!("/skipped/{}", filename.trim_start_matches('/')));
+        db.remove(key).unwrap();
+    }
+
+    // Skipped files and their reasons
+    pub fn skipped(&self) -> Vec<(String, String)> {
+        self.skipped_below("")
+    }
+
+    pub fn skipped_below(&self, prefix: &str) -> Vec<(String, String)> {
+        let db = self.db.as_ref().unwrap();
+        let mut skipped = Vec::new();
+        for kv in db.scan_prefix(format!("/skipped/{}", prefix.trim_start_matches('/'))) {
+            let (key, value) = kv.unwrap();
+            let filename = format!("/{}", String::from_utf8_lossy(&key["/skipped/".len()..]));
+            if path_contains(prefix, &filename) {
+                skipped.push((filename, String::from_utf8_lossy(value.as_ref()).to_string()));
+            }
+        }
+        skipped
+    }
+
+    // Paths removed on request are excluded from scans until added again
+    pub fn exclude(&self, path: &str) {
+        let db = self.db.as_ref().unwrap();
+        let key = std::fmt::format(format_args!("/excluded/{}", path.trim_start_matches('/')));
+        db.insert(key, "".as_bytes()).unwrap();
+    }
+
+    // Lifts exclusions of the path, anything below it and the directories
+    // above it
+    pub fn include(&self, path: &str) {
+        let db = self.db.as_ref().unwrap();
+        for excluded in self.excluded() {
+            if path_contains(&excluded, path) || path_contains(path, &excluded) {
+                let key = std::fmt::format(format_args!("/excluded/{}", excluded.trim_start_matches('/')));
+                db.remove(key).unwrap();
+            }
+        }
+    }
+
+    pub fn is_excluded(&self, filename: &str) -> bool {
+        self.excluded().iter().any(|excluded| path_contains(excluded, filename))
+    }
+
+    fn excluded(&self) -> Vec<String> {
+        let db = self.db.as_ref().unwrap();
+        db.scan_prefix("/excluded/")
+            .map(|kv| kv.unwrap().0)
+            .map(|key| format!("/{}", String::from_utf8_lossy(&key["/excluded/".len()..])))
+            .collect()
+    }
+
+    // Raw vectors are kept so that the faiss index can be trained and rebuilt
+    // without embedding every chunk again.
+    pub fn add_vector(&self, index: u64, vector: &[f32]) {
+        let tree = self.db.as_ref().unwrap().open_tree("vectors").unwrap();
+        let bytes = vector.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>();
+        tree.insert(index.to_be_bytes(), bytes).unwrap();
+    }
+
+    pub fn remove_vector(&self, index: u64) {
+        let tree = self.db.as_ref().unwrap().open_tree("vectors").unwrap();
+        tree.remove(index.to_be_bytes()).unwrap();
+    }
+
+    pub fn vector_count(&self) -> usize {
+        self.db.as_ref().unwrap().open_tree("vectors").unwrap().len()
+    }
+
+    pub fn vectors(&self) -> Vec<(u64, Vec<f32>)> {
+        let tree = self.db.as_ref().unwrap().open_tree("vectors").unwrap();
+        let mut vectors = Vec::new();
+        for kv in tree.iter() {
+            let (key, value) = kv.unwrap();
+            let index = u64::from_be_bytes(key.as_ref().try_into().unwrap());
+            let vector = value.chunks(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect::<Vec<f32>>();
+            vectors.push((index, vector));
         }
+        vectors
+    }
+
+    pub fn get_meta(&self, name: &str) -> Option<String> {
+        let db = self.db.as_ref().unwrap();
+        let key = std::fmt::format(format_args!("/meta/{}", name));
+        db.get(key).unwrap().map(|v| String::from_utf8_lossy(v.as_ref()).to_string())
+    }
+
+    pub fn set_meta(&self, name: &str, value: &str) {
+        let db = self.db.as_ref().unwrap();
+        let key = std::fmt::format(format_args!("/meta/{}", name));
+        db.insert(key, value.as_bytes()).unwrap();
     }
 
     pub fn is_file_in_catalog(&self, filename: String) -> bool {
@@ -109,3 +382,33 @@ impl Catalog {
         idg
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contains_paths_on_whole_components() {
+        assert!(path_contains("/a/b", "/a/b"));
+        assert!(path_contains("/a/b", "/a/b/c.txt"));
+        assert!(path_contains("/a/b/", "/a/b/c.txt"));
+        assert!(path_contains("/", "/a/b/c.txt"));
+        assert!(!path_contains("/a/b", "/a/bc"));
+        assert!(!path_contains("/a/b", "/a/bc/d.txt"));
+        assert!(!path_contains("/a/b/c.txt", "/a/b"));
+    }
+
+    #[test]
+    fn entries_below_leave_out_siblings() {
+        let catalog = Catalog::temporary();
+        for file in ["/a/b/x.txt", "/a/bc/y.txt", "/a/b.txt"] {
+            catalog.add(Entry::new(file.to_string(), Vec::new(), 0, 0, String::new()));
+        }
+        catalog.set_skipped("/a/bc/z.pdf", "parse_error", "bad xref");
+        let below = catalog.entries_below("/a/b").into_iter().map(|e| e.filename).collect::<Vec<String>>();
+        assert_eq!(below, vec![String::from("/a/b/x.txt")]);
+        assert!(catalog.skipped_below("/a/b").is_empty());
+        assert_eq!(catalog.entries_below("/a").len(), 3);
+        assert_eq!(catalog.entries().len(), 3);
+    }
+}
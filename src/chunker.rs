@@ -0,0 +1,323 @@
+// Copyright: (c) 2023 Sureshkumar T
+// License: Apache-2.0
+
+use nnsplit::{NNSplit, NNSplitOptions};
+use serde::{Serialize, Deserialize};
+
+use crate::settings;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chunk {
+    pub text: String,
+    // byte and character offsets of the chunk in the parsed document
+    pub start: usize,
+    pub end: usize,
+    pub char_start: usize,
+    pub char_end: usize,
+    // 1 based page number for documents with form feed separated pages
+    pub page: Option<u32>,
+    // headings of the section the chunk is in, outermost first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub section: Vec<String>,
+}
+
+// Where a section of a document with headings starts, as a byte offset in
+// its text, and its heading with those of the sections it is in
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub start: usize,
+    pub headings: Vec<String>,
+}
+
+pub struct Chunker {
+    splitter: Option<NNSplit>,
+    max_tokens: usize,
+    overlap_tokens: usize,
+}
+
+// Rough word piece count of a piece of text.  Long words are split into
+// several word pieces by the bert tokenizer, so this errs on the high side to
+// keep chunks within the model's sequence length.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.split_whitespace().map(|w| 1 + w.chars().count() / 6).sum()
+}
+
+impl Chunker {
+    pub fn new() -> Self {
+        let conf = settings::get_config(None);
+        // the splitter model is only read from disk; without one sentences
+        // end at punctuation
+        let splitter = match settings::get_splitter_path() {
+            Some(path) => match NNSplit::new(&path, NNSplitOptions::default()) {
+                Ok(splitter) => Some(splitter),
+                Err(e) => {
+                    log::warn!("Could not load sentence splitter from {}, splitting on punctuation: {}", path, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        Chunker {
+            splitter,
+            max_tokens: conf.chunk_tokens,
+            overlap_tokens: conf.chunk_overlap_tokens,
+        }
+    }
+
+    // Splits the text into sentences, returned with their byte offset.
+    fn sentences<'a>(&self, text: &'a str) -> Vec<(usize, &'a str)> {
+        let base = text.as_ptr() as usize;
+        let mut sentences = Vec::new();
+        if let Some(splitter) = &self.splitter {
+            let splits = splitter.split(&[text]);
+            for sentence in splits[0].iter() {
+                let s = sentence.text();
+                sentences.push((s.as_ptr() as usize - base, s));
+            }
+            return sentences;
+        }
+
+        let mut start = 0;
+        let mut prev = ' ';
+        for (i, c) in text.char_indices() {
+            if c.is_whitespace() && (prev == '.' || prev == '!' || prev == '?' || prev == '\n') {
+                sentences.push((start, &text[start..i]));
+                start = i;
+            }
+            prev = c;
+        }
+        if start < text.len() {
+            sentences.push((start, &text[start..]));
+        }
+        sentences
+    }
+
+    // Breaks a sentence that alone exceeds the token budget at whitespace.
+    fn split_long(&self, offset: usize, sentence: &str) -> Vec<(usize, usize)> {
+        let mut pieces = Vec::new();
+        let mut start = 0;
+        let mut tokens = 0;
+        let mut last_end = 0;
+        for (i, word) in sentence.split_whitespace().map(|w| (w.as_ptr() as usize - sentence.as_ptr() as usize, w)) {
+            let t = estimate_tokens(word);
+            if tokens + t > self.max_tokens && tokens > 0 {
+                pieces.push((offset + start, offset + last_end));
+                start = i;
+                tokens = 0;
+            }
+            tokens += t;
+            last_end = i + word.len();
+        }
+        if tokens > 0 {
+            pieces.push((offset + start, offset + last_end));
+        }
+        pieces
+    }
+
+    // Chunks each section separately, so no chunk spans two sections and
+    // each carries the headings of its own.  Text before the first section
+    // has no headings.
+    pub fn chunk_sections(&self, text: &str, sections: &[Section]) -> Vec<Chunk> {
+        if sections.len() == 0 {
+            return self.chunk(text);
+        }
+        let mut chunks = Vec::new();
+        let mut bounds = vec![(0, Vec::new())];
+        bounds.extend(sections.iter().map(|s| (s.start.min(text.len()), s.headings.clone())));
+        for i in 0..bounds.len() {
+            let start = bounds[i].0;
+            let end = bounds.get(i + 1).map(|b| b.0).unwrap_or(text.len());
+            if end <= start {
+                continue;
+            }
+            for mut chunk in self.pack(&text[start..end]) {
+                chunk.start += start;
+                chunk.end += start;
+                chunk.section = bounds[i].1.clone();
+                chunks.push(chunk);
+            }
+        }
+        Self::locate(text, &mut chunks);
+        chunks
+    }
+
+    pub fn chunk(&self, text: &str) -> Vec<Chunk> {
+        let mut chunks = self.pack(text);
+        Self::locate(text, &mut chunks);
+        chunks
+    }
+
+    // Packs consecutive sentences into chunks of at most `max_tokens`
+    // estimated tokens.  Each chunk starts with the trailing sentences of the
+    // previous one, up to `overlap_tokens`, so that an answer spanning a chunk
+    // boundary is still found.
+    fn pack(&self, text: &str) -> Vec<Chunk> {
+        // byte ranges of sentences, or pieces of overlong sentences
+        let mut units: Vec<(usize, usize)> = Vec::new();
+        for (offset, sentence) in self.sentences(text) {
+            if sentence.trim().len() == 0 {
+                continue;
+            }
+            if estimate_tokens(sentence) > self.max_tokens {
+                units.extend(self.split_long(offset, sentence));
+            } else {
+                units.push((offset, offset + sentence.len()));
+            }
+        }
+
+        let mut chunks = Vec::new();
+        let mut current: Vec<usize> = Vec::new();
+        let mut tokens = 0;
+        for i in 0..units.len() {
+            let t = estimate_tokens(&text[units[i].0..units[i].1]);
+            if tokens + t > self.max_tokens && current.len() > 0 {
+                chunks.push(Self::make_chunk(text, &units, &current));
+                // keep the tail of the previous chunk as overlap
+                let mut kept = Vec::new();
+                let mut kept_tokens = 0;
+                for j in current.iter().rev() {
+                    let tj = estimate_tokens(&text[units[*j].0..units[*j].1]);
+                    if kept_tokens + tj > self.overlap_tokens || kept_tokens + tj + t > self.max_tokens {
+                        break;
+                    }
+                    kept.insert(0, *j);
+                    kept_tokens += tj;
+                }
+                current = kept;
+                tokens = kept_tokens;
+            }
+            current.push(i);
+            tokens += t;
+        }
+        if current.len() > 0 {
+            chunks.push(Self::make_chunk(text, &units, &current));
+        }
+        chunks
+    }
+
+    fn make_chunk(text: &str, units: &Vec<(usize, usize)>, members: &Vec<usize>) -> Chunk {
+        let start = units[members[0]].0;
+        let end = units[members[members.len() - 1]].1;
+        Chunk {
+            text: text[start..end].to_string(),
+            start,
+            end,
+            char_start: 0,
+            char_end: 0,
+            page: None,
+            section: Vec::new(),
+        }
+    }
+
+    // Fills in character offsets and page numbers.  Chunks are ordered by
+    // start, so a single pass over the text is enough.  A chunk starting with
+    // the whitespace after a sentence may start with the page break before
+    // its text, and is on the page after it.
+    fn locate(text: &str, chunks: &mut Vec<Chunk>) {
+        let paged = text.contains('\x0c');
+        let mut pos = 0;
+        let mut chars = 0;
+        let mut page = 1;
+        for chunk in chunks.iter_mut() {
+            for c in text[pos..chunk.start].chars() {
+                chars += 1;
+                if c == '\x0c' {
+                    page += 1;
+                }
+            }
+            pos = chunk.start;
+            chunk.char_start = chars;
+            chunk.char_end = chars + chunk.text.chars().count();
+            if paged {
+                let leading = &chunk.text[..chunk.text.len() - chunk.text.trim_start().len()];
+                chunk.page = Some(page + leading.matches('\x0c').count() as u32);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunker(max_tokens: usize, overlap_tokens: usize) -> Chunker {
+        Chunker { splitter: None, max_tokens, overlap_tokens }
+    }
+
+    #[test]
+    fn chunks_fit_the_token_budget() {
+        let chunker = chunker(12, 4);
+        let mut text = (1..=20).map(|i| format!("Sentence number {} is here.", i)).collect::<Vec<String>>().join(" ");
+        // one sentence that alone is over the budget
+        text.push_str(" ");
+        text.push_str(&vec!["word"; 40].join(" "));
+        let chunks = chunker.chunk(&text);
+        assert!(chunks.len() > 20);
+        for chunk in chunks.iter() {
+            assert!(estimate_tokens(&chunk.text) <= 12, "{:?}", chunk.text);
+            assert_eq!(&text[chunk.start..chunk.end], chunk.text);
+        }
+        assert!(chunks[0].text.starts_with("Sentence number 1 is here."));
+        assert!(chunks[chunks.len() - 1].text.ends_with("word word"));
+    }
+
+    #[test]
+    fn chunks_overlap_by_trailing_sentences() {
+        let chunker = chunker(6, 2);
+        // two tokens a sentence
+        let text = "Alpha one. Beta two. Gamma six. Delta ten.";
+        let chunks = chunker.chunk(text);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].text, "Alpha one. Beta two. Gamma six.");
+        assert_eq!(chunks[1].text, " Gamma six. Delta ten.");
+        assert!(chunks[1].start < chunks[0].end);
+    }
+
+    #[test]
+    fn long_sentences_are_split_at_whitespace() {
+        let chunker = chunker(5, 0);
+        let pieces = chunker.split_long(100, "a b c d e f g h i j k l");
+        assert_eq!(pieces, vec![(100, 109), (110, 119), (120, 123)]);
+    }
+
+    #[test]
+    fn chunks_carry_the_headings_of_their_section() {
+        let chunker = chunker(50, 10);
+        let text = "Intro text.\n# A\nAlpha body.\n# B\nBeta body.";
+        let a = text.find("# A").unwrap();
+        let b = text.find("# B").unwrap();
+        let sections = vec![
+            Section { start: a, headings: vec![String::from("A")] },
+            Section { start: b, headings: vec![String::from("B")] },
+        ];
+        let chunks = chunker.chunk_sections(text, &sections);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].text, "Intro text.");
+        assert!(chunks[0].section.is_empty());
+        assert_eq!(chunks[1].text, "# A\nAlpha body.");
+        assert_eq!((chunks[1].start, chunks[1].end), (a, b - 1));
+        assert_eq!(chunks[1].section, vec![String::from("A")]);
+        assert_eq!((chunks[2].start, chunks[2].end), (b, text.len()));
+        assert_eq!(chunks[2].section, vec![String::from("B")]);
+    }
+
+    #[test]
+    fn locates_pages_and_characters() {
+        let chunker = chunker(4, 0);
+        let text = "Ünïcode first page.\x0cSecond page here.\x0cThird.";
+        let chunks = chunker.chunk(text);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.iter().map(|c| c.page).collect::<Vec<Option<u32>>>(), vec![Some(1), Some(2), Some(3)]);
+        assert_eq!((chunks[0].char_start, chunks[0].char_end), (0, 19));
+        // Ü and ï take two bytes each
+        assert_eq!(chunks[1].start, 21);
+        assert_eq!((chunks[1].char_start, chunks[1].char_end), (19, 37));
+        assert_eq!((chunks[2].char_start, chunks[2].char_end), (37, 44));
+    }
+
+    #[test]
+    fn text_without_page_breaks_has_no_pages() {
+        let chunks = chunker(50, 10).chunk("One page only.");
+        assert_eq!(chunks[0].page, None);
+    }
+}
@@ -3,28 +3,26 @@
 
 mod settings;
 mod error;
-mod indexer;
-mod idgenerator;
-mod catalog;
+mod protocol;
+mod shell;
+mod opener;
 
 use clap;
 use clap::{Arg, Parser, Subcommand, ArgMatches};
 
 use std::error::Error;
-use std::path::PathBuf;
+use std::env;
+use std::path::{Component, Path, PathBuf};
 use std::fs;
-use std::fs::File;
 use std::io::prelude::*;
+use std::io::BufReader;
 use std::os::unix::net::UnixStream;
-use std::sync::Arc;
 
 use tracing;
 use tracing_subscriber::prelude::*;
 use tracing_subscriber;
 use tracing::Level as LogLevel;
 
-use indexer::Indexer;
-
 #[derive(Parser, Debug)]
 #[command(name = "semdesk-cli")]
 struct Cli {
@@ -41,21 +39,294 @@ enum Commands {
     Query {
         #[arg(required = true)]
         query: String,
+
+        /// how to find documents: semantic, lexical or hybrid
+        #[arg(short, long)]
+        mode: Option<String>,
+
+        /// number of passages to retrieve
+        #[arg(long)]
+        top_k: Option<usize>,
+
+        /// drop passages less similar to the query than this
+        #[arg(long)]
+        min_score: Option<f32>,
+
+        /// IVF lists to search, for IVF indexes
+        #[arg(long)]
+        nprobe: Option<u32>,
+
+        /// candidate list size, for HNSW indexes
+        #[arg(long)]
+        ef_search: Option<u32>,
+
+        /// only search files below this directory
+        #[arg(long)]
+        path: Option<String>,
+
+        /// plain for reading, json, jsonl or tsv for scripts
+        #[arg(short, long, value_enum, default_value = "plain")]
+        format: Format,
+    },
+
+    /// open a result of the last query at the matching line or page
+    #[command(name = "open")]
+    Open {
+        #[arg(required = true)]
+        result: usize,
     },
 
+    /// ask questions one after the other, with history
+    #[command(name = "shell")]
+    Shell,
+
+    /// show what the daemon has indexed
+    #[command(name = "status")]
+    Status,
+
+    /// list the indexed and skipped files below a path
+    #[command(name = "ls")]
+    Ls {
+        prefix: Option<String>,
+
+        /// plain for reading, json for scripts
+        #[arg(short, long, value_enum, default_value = "plain")]
+        format: ListFormat,
+    },
+
+    /// show what is indexed for a file
+    #[command(name = "show")]
+    Show {
+        #[arg(required = true)]
+        path: String,
+
+        /// plain for reading, json for scripts
+        #[arg(short, long, value_enum, default_value = "plain")]
+        format: ListFormat,
+    },
+
+    /// index a file or directory now
     #[command(name = "add")]
-    AddDocument {
+    Add {
+        #[arg(required = true)]
+        path: String,
+    },
+
+    /// drop a file or directory from the index and stop indexing it
+    #[command(name = "remove")]
+    Remove {
         #[arg(required = true)]
-        location: String,
+        path: String,
+    },
+
+    /// embed a file or directory again, or everything if no path is given
+    #[command(name = "reindex")]
+    Reindex {
+        path: Option<String>,
     },
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Plain,
+    // an array of all results, best first
+    Json,
+    // one result per line as they arrive
+    Jsonl,
+    // a header line, then one result per line as they arrive
+    Tsv,
+}
+
+// Formats of ls and show, whose output isn't a stream of results
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum ListFormat {
+    Plain,
+    Json,
+}
+
+// Field names of the json and tsv formats.  Scripts depend on these, so only
+// ever add to them.
+const RESULT_FIELDS: [&str; 12] = ["file", "score", "answer", "chunk_text", "start", "end", "char_start", "char_end", "page", "chunk_id", "retrieval_score", "section"];
+
+// The file followed by the headings of the section a result is in, e.g.
+// notes.md › Setup › Install
+pub fn breadcrumbs(file: &str, section: &[String]) -> String {
+    let mut crumbs = vec![file];
+    crumbs.extend(section.iter().map(|s| s.as_str()));
+    crumbs.join(" › ")
+}
+
+fn result_json(r: &protocol::SearchResult) -> serde_json::Value {
+    serde_json::json!({
+        "file": r.file,
+        "score": r.score,
+        "answer": r.answer,
+        "chunk_text": r.chunk_text,
+        "start": r.start,
+        "end": r.end,
+        "char_start": r.char_start,
+        "char_end": r.char_end,
+        "page": r.page,
+        "chunk_id": r.chunk_id,
+        "retrieval_score": r.retrieval_score,
+        "section": r.section,
+    })
+}
+
+// Tabs and newlines in text are escaped so each result stays on one line
+fn tsv_field(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn result_tsv(r: &protocol::SearchResult) -> String {
+    let page = r.page.map(|p| p.to_string()).unwrap_or_default();
+    format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            tsv_field(&r.file), r.score, tsv_field(&r.answer), tsv_field(&r.chunk_text),
+            r.start, r.end, r.char_start, r.char_end, page, r.chunk_id, r.retrieval_score,
+            tsv_field(&r.section.join(" › ")))
+}
+
+fn request_id() -> String {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+    format!("{}-{}", std::process::id(), now.as_millis())
+}
+
+// An error response from the daemon
 #[derive(Debug)]
-struct QueryResult {
-    id: String,
-    loc: String,
-    text: String,
-    score: f32,
+struct DaemonError(String);
+
+impl std::fmt::Display for DaemonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for DaemonError {}
+
+// A connection to the daemon.  It can carry any number of requests, one after
+// the other.
+struct Connection {
+    socket: UnixStream,
+    reader: BufReader<UnixStream>,
+}
+
+impl Connection {
+    fn open() -> Result<Self, Box<dyn Error>> {
+        let socket = UnixStream::connect(settings::get_socket_path())?;
+        let reader = BufReader::new(socket.try_clone()?);
+        Ok(Connection { socket, reader })
+    }
+
+    // Sends the request and passes each response to `handle`, up to and
+    // including the `done` one.  An error response is returned as a
+    // DaemonError.  Responses to other requests are skipped; only errors the
+    // daemon couldn't tie to a request, like being busy, come without an id.
+    fn request<F: FnMut(protocol::ResponseBody)>(&mut self, body: protocol::RequestBody, mut handle: F) -> Result<(), Box<dyn Error>> {
+        let id = request_id();
+        protocol::write_message(&mut self.socket, &protocol::Request::new(id.clone(), body))?;
+        while let Some(line) = protocol::read_line(&mut self.reader)? {
+            tracing::debug!("Parsing response: {}", line);
+            let response: protocol::Response = match serde_json::from_str(&line) {
+                Ok(response) => response,
+                Err(e) => {
+                    tracing::warn!("Ignoring malformed response: {}", e);
+                    continue;
+                }
+            };
+            let anonymous_error = response.id.is_empty() && matches!(response.body, protocol::ResponseBody::Error { .. });
+            if response.id != id && !anonymous_error {
+                tracing::debug!("Ignoring response to request {}", response.id);
+                continue;
+            }
+            match response.body {
+                protocol::ResponseBody::Error { message } => return Err(Box::new(DaemonError(message))),
+                protocol::ResponseBody::Done { count } => {
+                    handle(protocol::ResponseBody::Done { count });
+                    return Ok(());
+                }
+                body => handle(body),
+            }
+        }
+        Err(Box::new(error::Error::new("daemon closed the connection")))
+    }
+}
+
+// Sends the request to the daemon on a connection of its own and passes each
+// response to `handle`.  Exits on an error response.
+fn request<F: FnMut(protocol::ResponseBody)>(body: protocol::RequestBody, handle: F) -> Result<(), Box<dyn Error>> {
+    let result = Connection::open()?.request(body, handle);
+    if let Err(e) = &result {
+        if e.is::<DaemonError>() {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+    result
+}
+
+// Prints the progress of an add, remove or reindex request and its summary
+fn admin(body: protocol::RequestBody) -> Result<(), Box<dyn Error>> {
+    request(body, |body| {
+        match body {
+            protocol::ResponseBody::Progress { file, action, detail } => {
+                match detail {
+                    Some(detail) => println!("{:<10}{} ({})", action, file, detail),
+                    None => println!("{:<10}{}", action, file),
+                }
+            }
+            protocol::ResponseBody::Summary { indexed, unchanged, removed, skipped, failed } => {
+                println!("{} indexed, {} unchanged, {} removed, {} skipped, {} failed", indexed, unchanged, removed, skipped, failed);
+            }
+            _ => {}
+        }
+    })
+}
+
+fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+// The working directory as the shell has it, with the symlinks the user
+// went through, as long as $PWD is still where we are
+fn current_dir() -> Option<PathBuf> {
+    let cwd = env::current_dir().ok()?;
+    match env::var("PWD") {
+        Ok(pwd) if Path::new(&pwd).is_absolute() && fs::canonicalize(&pwd).ok().as_ref() == Some(&cwd) => Some(PathBuf::from(pwd)),
+        _ => Some(cwd),
+    }
+}
+
+// Filters are matched against the absolute paths in the catalog.  Those are
+// below the configured roots as written, so symlinks are kept and only . and
+// .. are resolved, the way the shell does.
+fn absolute_path(path: &str) -> String {
+    let path = PathBuf::from(settings::expand_home(path));
+    let path = match (path.is_absolute(), current_dir()) {
+        (false, Some(cwd)) => cwd.join(path),
+        _ => path,
+    };
+    let mut absolute = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            component => absolute.push(component),
+        }
+    }
+    absolute.to_string_lossy().to_string()
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -74,57 +345,186 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
 
     match matches.command {
-        Commands::Query { query } => {
+        Commands::Query { query, mode, top_k, min_score, nprobe, ef_search, path, format } => {
             tracing::debug!("Query command");
 
-            let socket_path = settings::get_socket_path();
-            let mut socket = UnixStream::connect(socket_path)?;
-
-            socket.write_all(query.as_bytes())?;
-            // get the results from the unix domain socket
-            let mut results = String::new();
-            socket.read_to_string(&mut results)?;
-
-            // parse the results into a result struct. The string is of the format split by |
-            // id|loc|text|score
-            let mut results: Vec<QueryResult> = results.split("\n")
-                .filter(|r| !r.trim().is_empty())
-                .map(|r| {
-                    tracing::debug!("Parsing result: {}", r);
-                    let mut r = r.split("|");
-                    QueryResult {
-                        id: r.next().unwrap().to_string(),
-                        loc: r.next().unwrap().to_string(),
-                        text: r.next().unwrap().to_string(),
-                        score: r.next().unwrap().parse::<f32>().unwrap(),
+            let options = protocol::QueryOptions {
+                mode,
+                top_k,
+                min_score,
+                nprobe,
+                ef_search,
+                filters: protocol::Filters { path_prefix: path.map(|p| absolute_path(&p)) },
+            };
+
+            if format == Format::Tsv {
+                println!("{}", RESULT_FIELDS.join("\t"));
+            }
+            // print the responses as they arrive: the retrieved passages
+            // first, then the answers found in each
+            let mut answers = 0;
+            let mut all: Vec<protocol::SearchResult> = Vec::new();
+            request(protocol::RequestBody::Query { query, options }, |body| {
+                match body {
+                    protocol::ResponseBody::Hits { hits } => {
+                        if format == Format::Plain {
+                            println!("Found {} passages:", hits.len());
+                            for h in hits.iter() {
+                                println!("  {} ({:.2})", h.file, h.score);
+                            }
+                            println!();
+                        }
                     }
-                })
-                .collect();
-            
-            // sort the results by score descending
-            results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
-            println!("Results:");
-            for r in results {
-                println!("File: {}", r.loc);
-                println!("Match Probability: {:.2}%", r.score*100.0);
-                println!("{}\n", r.text);
+                    protocol::ResponseBody::Results { mut results } => {
+                        if answers == 0 && format == Format::Plain {
+                            println!("Results:");
+                        }
+                        answers += results.len();
+                        results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+                        for r in results {
+                            match format {
+                                Format::Plain => {
+                                    println!("[{}] File: {}", all.len() + 1, breadcrumbs(&r.file, &r.section));
+                                    println!("Match Probability: {:.2}%", r.score*100.0);
+                                    println!("{}\n", r.answer);
+                                }
+                                Format::Jsonl => println!("{}", result_json(&r)),
+                                Format::Tsv => println!("{}", result_tsv(&r)),
+                                Format::Json => {}
+                            }
+                            all.push(r);
+                        }
+                    }
+                    _ => {}
+                }
+            })?;
+            if format == Format::Json {
+                all.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+                let json = all.iter().map(result_json).collect::<Vec<serde_json::Value>>();
+                println!("{}", serde_json::to_string_pretty(&json)?);
+            } else if answers == 0 && format == Format::Plain {
+                println!("No answers found");
             }
+            // numbered as printed, for `semdesk-cli open`
+            opener::save_results(&all);
 
         },
-        Commands::AddDocument { location } => {
-            tracing::warn!("this command is only for testing");
-            let catalog = catalog::Catalog::new();
-            let arc_catalog = Arc::new(catalog);
-            let mut ind: indexer::IndexerImpl = indexer::IndexerImpl::new(idgenerator::IdGenerator::new(arc_catalog.clone()));
-            let mut file = File::open(location.clone())?;
-            let mut contents = String::new();
-            file.read_to_string(&mut contents)?;
-            ind.add_document(contents, 0, location);
-            // wait for user input to exit
-            let mut input = String::new();
-            std::io::stdin().read_line(&mut input)?;
-        }
+        Commands::Open { result } => {
+            let results = opener::load_results();
+            match result.checked_sub(1).and_then(|i| results.get(i)) {
+                Some(r) => opener::open(r)?,
+                None => {
+                    eprintln!("Error: no result {}, the last query had {}", result, results.len());
+                    std::process::exit(1);
+                }
+            }
+        },
+        Commands::Shell => {
+            shell::run()?;
+        },
+        Commands::Status => {
+            request(protocol::RequestBody::Status, |body| {
+                if let protocol::ResponseBody::Status { status } = body {
+                    println!("Files:           {}", status.files);
+                    println!("Vectors:         {} ({})", status.vectors, status.index_factory);
+                    println!("Last scan:       {}", status.last_scan.unwrap_or(String::from("never")));
+                    println!("Indexer queue:   {}", status.queue_depth);
+                    let skipped: usize = status.skipped.values().sum();
+                    println!("Skipped files:   {}", skipped);
+                    for (reason, count) in status.skipped.iter() {
+                        println!("  {:<15}{}", reason, count);
+                    }
+                    println!("Embedding model: {}", status.embedding_model);
+                    println!("QA model:        {}", status.qa_model);
+                    println!("Database size:   {}", human_bytes(status.db_bytes));
+                    println!("Index size:      {}", human_bytes(status.index_bytes));
+                }
+            })?;
+        },
+        Commands::Ls { prefix, format } => {
+            let mut files = Vec::new();
+            request(protocol::RequestBody::List { prefix: prefix.map(|p| absolute_path(&p)) }, |body| {
+                match body {
+                    protocol::ResponseBody::Files { files: batch } => files.extend(batch),
+                    _ => {}
+                }
+            })?;
+            if format == ListFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&files)?);
+            } else {
+                for f in files.iter() {
+                    let detail = f.detail.as_ref().map(|d| format!(" ({})", d)).unwrap_or_default();
+                    println!("{:<17}{:>7}{:>10}  {:<26}{}{}", f.status, f.chunks, human_bytes(f.size), f.indexed_at.clone().unwrap_or(String::from("-")), f.path, detail);
+                }
+            }
+        },
+        Commands::Show { path, format } => {
+            request(protocol::RequestBody::Show { path: absolute_path(&path) }, |body| {
+                if let protocol::ResponseBody::File { file, chunks } = body {
+                    if format == ListFormat::Json {
+                        println!("{}", serde_json::to_string_pretty(&serde_json::json!({ "file": file, "chunks": chunks })).unwrap());
+                        return;
+                    }
+                    println!("Path:       {}", file.path);
+                    match &file.detail {
+                        Some(detail) => println!("Status:     {} ({})", file.status, detail),
+                        None => println!("Status:     {}", file.status),
+                    }
+                    println!("Mime type:  {}", file.mime);
+                    println!("Size:       {}", human_bytes(file.size));
+                    println!("Indexed at: {}", file.indexed_at.unwrap_or(String::from("-")));
+                    println!("Hash:       {}", file.hash);
+                    println!("Chunks:     {}", file.chunks);
+                    println!("Vector ids: {:?}", file.vector_ids);
+                    for (key, value) in file.metadata.iter() {
+                        println!("{:<12}{}", format!("{}:", key), value);
+                    }
+                    for c in chunks.iter() {
+                        let page = c.page.map(|p| format!(" page {}", p)).unwrap_or_default();
+                        println!("\n[{}] bytes {}..{}{}", c.id, c.start, c.end, page);
+                        if c.section.len() > 0 {
+                            println!("{}", c.section.join(" › "));
+                        }
+                        println!("{}", c.text);
+                    }
+                }
+            })?;
+        },
+        Commands::Add { path } => {
+            admin(protocol::RequestBody::Add { path: absolute_path(&path) })?;
+        },
+        Commands::Remove { path } => {
+            admin(protocol::RequestBody::Remove { path: absolute_path(&path) })?;
+        },
+        Commands::Reindex { path } => {
+            admin(protocol::RequestBody::Reindex { path: path.map(|p| absolute_path(&p)) })?;
+        },
     }
     Ok(())
 }
     
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn absolute_path_keeps_symlinks() {
+        let dir = env::temp_dir().join(format!("semdesk-cli-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("real")).unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+        let link = dir.join("link").to_str().unwrap().to_string();
+        assert_eq!(absolute_path(&format!("{}/notes.txt", link)), format!("{}/notes.txt", link));
+        assert_eq!(absolute_path(&format!("{}/./a/../notes.txt", link)), format!("{}/notes.txt", link));
+        assert_eq!(absolute_path(&format!("{}/", link)), link);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn absolute_path_of_relative_paths() {
+        let cwd = current_dir().unwrap();
+        assert_eq!(absolute_path("notes/a.txt"), cwd.join("notes/a.txt").to_str().unwrap());
+        assert_eq!(absolute_path("."), cwd.to_str().unwrap());
+    }
+}
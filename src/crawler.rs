@@ -2,128 +2,543 @@
 // License: Apache-2.0
 
 use std::sync::mpsc::channel;
-use std::sync::mpsc::{Receiver, Sender};
-use std::thread;
-use std::time::Duration;
-use dirs;
-use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, Sender, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::error::Error as StdError;
 use log;
 use chrono;
+use sha256;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 
 use crate::settings;
 use crate::catalog::Catalog;
 use crate::catalog;
-use crate::parsers::Parser;
+use crate::parsers::{Document, Registry};
 use crate::indexer;
+use crate::error::UnsupportedFileTypeError;
 
 pub trait Crawler {
     fn new(catalog: Arc<Catalog>, indexer_ch: Sender<indexer::Message>) -> Self;
     fn scan(&self);
+    fn reconcile(&self);
     fn run(&mut self);
 }
 
+// Requests to index or drop files right away rather than on the next scan
+pub enum Message {
+    Add(String, Sender<Reply>),
+    Remove(String, Sender<Reply>),
+    // a file or directory, or everything in the catalog
+    Reindex(Option<String>, Sender<Reply>),
+}
+
+pub enum Reply {
+    Progress(String, Outcome),
+    Summary(Summary),
+}
+
+// What became of a file
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    // with the number of chunks
+    Indexed(usize),
+    Unchanged,
+    // from the path it was catalogued under
+    Moved(String),
+    Removed,
+    Skipped(String),
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    pub indexed: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl Summary {
+    fn count(&mut self, outcome: &Outcome) {
+        match outcome {
+            Outcome::Indexed(_) | Outcome::Moved(_) => self.indexed += 1,
+            Outcome::Unchanged => self.unchanged += 1,
+            Outcome::Removed => self.removed += 1,
+            Outcome::Skipped(_) => self.skipped += 1,
+            Outcome::Failed(_) => self.failed += 1,
+        }
+    }
+}
+
 pub struct CrawlerImpl {
     catalog: Arc<Catalog>,
+    parsers: Registry,
     indexer_channel: Sender<indexer::Message>,
+    admin_channel: (Sender<Message>, Receiver<Message>),
+    // changed paths and admin requests waiting to be handled.  Whoever sends
+    // an admin request counts it, and it is counted off once it is done.
+    queue: Arc<AtomicUsize>,
 }
 
 
 impl CrawlerImpl {
-    fn is_file_indexed(&self, filename: String) -> bool {
-        return self.catalog.is_file_in_catalog(filename);
+    fn indexed_entry(&self, filename: String) -> Option<catalog::Entry> {
+        return self.catalog.get_by_file(filename).ok();
+    }
+
+    fn file_stat(metadata: &std::fs::Metadata) -> (u64, u64) {
+        let mtime = metadata.modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        (mtime, metadata.len())
+    }
+
+    fn remove_vectors(&self, ids: Vec<u64>) {
+        let ch = channel();
+        self.indexer_channel.send(indexer::Message::RemoveDocument(ids, ch.0)).unwrap();
+        if let Ok(indexer::Reply::Removed(n)) = ch.1.recv() {
+            log::debug!("Removed {} stale vectors", n);
+        }
+    }
+
+    fn roots() -> Vec<String> {
+        let mut roots = Vec::new();
+        for filename in settings::get_config(None).files.iter() {
+            roots.push(settings::expand_home(filename));
+        }
+        roots
+    }
+
+    // A root that is unreadable or gone is logged and left for the next scan
+    fn scan_roots(&self, roots: &Vec<String>) {
+        for fname in roots {
+            log::debug!("Scanning: {}", fname);
+            if PathBuf::from(&fname).is_dir() {
+                let entries = match std::fs::read_dir(fname) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        log::warn!("Cannot scan {}: {}", fname, e);
+                        continue;
+                    }
+                };
+                for entry in entries {
+                    log::debug!("Dir listing: {:?} {:?}", fname, entry);
+                    let path = match entry {
+                        Ok(entry) => entry.path(),
+                        Err(e) => {
+                            log::warn!("Cannot scan an entry of {}: {}", fname, e);
+                            continue;
+                        }
+                    };
+                    match path.to_str() {
+                        Some(fname) => self.scan_file(fname.to_string(), 0),
+                        None => log::warn!("Skipping {:?}, its name is not valid UTF-8", path),
+                    }
+                }
+            } else {
+                self.scan_file(fname.clone(), 0);
+            }
+        }
+    }
+
+    // Returns the watcher and the roots for which a watch could not be set.
+    fn start_watcher(roots: &Vec<String>, tx: Sender<notify::Result<notify::Event>>) -> (Option<RecommendedWatcher>, Vec<String>) {
+        let mut watcher = match notify::recommended_watcher(move |res: notify::Result<notify::Event>| { let _ = tx.send(res); }) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                log::warn!("Could not start file watcher, falling back to periodic scan: {}", e);
+                return (None, roots.clone());
+            }
+        };
+        let mut unwatched = Vec::new();
+        let mut watching = 0;
+        for root in roots {
+            match watcher.watch(Path::new(root), RecursiveMode::Recursive) {
+                Ok(_) => {
+                    log::info!("Watching: {}", root);
+                    watching += 1;
+                }
+                Err(e) => {
+                    log::warn!("Could not watch {}, falling back to periodic scan: {}", root, e);
+                    unwatched.push(root.clone());
+                }
+            }
+        }
+        if watching == 0 {
+            return (None, unwatched);
+        }
+        (Some(watcher), unwatched)
+    }
+
+    // Depth of a path below the configured root containing it, as passed to
+    // scan_file, or None if it is outside the roots or inside a hidden
+    // directory (which includes the db and index in local mode).
+    fn depth_in_roots(path: &Path) -> Option<u32> {
+        for root in Self::roots() {
+            let rel = match path.strip_prefix(&root) {
+                Ok(rel) => rel,
+                Err(_) => continue,
+            };
+            let components = rel.components().map(|c| c.as_os_str().to_string_lossy().to_string()).collect::<Vec<String>>();
+            if components.iter().any(|c| c.starts_with(".")) {
+                return None;
+            }
+            if components.len() == 0 {
+                return Some(0);
+            }
+            return Some(components.len() as u32 - 1);
+        }
+        None
+    }
+
+    // Removes a deleted file, or every file below a deleted directory, from
+    // the catalog and index.  Returns the files removed.
+    fn remove_path(&self, path: &Path) -> Vec<String> {
+        let path = path.to_string_lossy();
+        let mut removed = Vec::new();
+        for entry in self.catalog.entries() {
+            if !catalog::path_contains(&path, &entry.filename) {
+                continue;
+            }
+            log::debug!("File deleted: {}", entry.filename);
+            self.remove_vectors(entry.indexes.clone());
+            self.catalog.delete(entry.filename.clone());
+            removed.push(entry.filename);
+        }
+        for (filename, _) in self.catalog.skipped() {
+            if catalog::path_contains(&path, &filename) {
+                self.catalog.clear_skipped(&filename);
+            }
+        }
+        removed
+    }
+
+    // Handles watch events for up to `period`.  Events are debounced per path
+    // so that an editor saving a file in several writes triggers one re-index.
+    fn process_events(&self, events: &Receiver<notify::Result<notify::Event>>, period: Duration) {
+        let debounce = Duration::from_millis(settings::get_config(None).watch_debounce_ms);
+        let deadline = Instant::now() + period;
+        let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+        while Instant::now() < deadline || pending.len() > 0 {
+            match events.recv_timeout(debounce) {
+                Ok(Ok(event)) => {
+                    if !matches!(event.kind, EventKind::Access(_)) {
+                        for path in event.paths {
+                            if pending.insert(path, Instant::now()).is_none() {
+                                self.queue.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                }
+                Ok(Err(e)) => log::warn!("Watch error: {}", e),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    log::error!("File watcher stopped");
+                    self.queue.fetch_sub(pending.len(), Ordering::Relaxed);
+                    self.serve_admin(deadline.saturating_duration_since(Instant::now()));
+                    return;
+                }
+            }
+            while let Ok(msg) = self.admin_channel.1.try_recv() {
+                self.admin(msg);
+            }
+
+            let now = Instant::now();
+            let ready = pending.iter()
+                .filter(|(_, seen)| now.duration_since(**seen) >= debounce)
+                .map(|(path, _)| path.clone())
+                .collect::<Vec<PathBuf>>();
+            for path in ready.iter() {
+                pending.remove(path);
+            }
+            // index paths that exist before purging vanished ones, so that the
+            // target of a rename is matched by content hash and keeps its vectors
+            let (present, gone): (Vec<PathBuf>, Vec<PathBuf>) = ready.into_iter().partition(|p| p.exists());
+            for path in present {
+                if let Some(depth) = Self::depth_in_roots(&path) {
+                    log::debug!("Changed: {:?}", path);
+                    self.scan_file(path.to_str().unwrap().to_string(), depth);
+                }
+                self.queue.fetch_sub(1, Ordering::Relaxed);
+            }
+            for path in gone {
+                if Self::depth_in_roots(&path).is_some() {
+                    self.remove_path(&path);
+                }
+                self.queue.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
     }
 
     fn scan_file(&self, filename: String, depth: u32) {
+        self.scan_path(filename, depth, false, &mut |_, _| {});
+    }
+
+    // Indexes a file, or the files below a directory, reporting what became
+    // of each.  `force` re-embeds files even if they are unchanged.
+    fn scan_path(&self, filename: String, depth: u32, force: bool, report: &mut dyn FnMut(&str, Outcome)) {
         if depth > settings::get_config(None).max_scan_depth {
             return;
         }
-        if self.is_file_indexed(filename.clone()) {
-            return;
-        }
         if PathBuf::from(&filename).is_file() {
-            // don't index if size is greater than 10 MB
-            let metadata = std::fs::metadata(&filename).unwrap();
-            if metadata.len() > 10 * 1024 * 1024 {
-                return;
+            let outcome = self.index_file(&filename, force);
+            report(&filename, outcome);
+        } else if PathBuf::from(&filename).is_dir() {
+            let entries = match std::fs::read_dir(&filename) {
+                Ok(entries) => entries,
+                Err(e) => return report(&filename, Outcome::Failed(e.to_string())),
+            };
+            for entry in entries {
+                let path = match entry {
+                    Ok(entry) => entry.path(),
+                    Err(e) => {
+                        report(&filename, Outcome::Failed(e.to_string()));
+                        continue;
+                    }
+                };
+                match path.to_str() {
+                    Some(fname) => self.scan_path(fname.to_string(), depth + 1, force, report),
+                    None => report(&path.to_string_lossy(), Outcome::Failed(String::from("file name is not valid UTF-8"))),
+                }
             }
+        }
+    }
 
-            // don't index if file is hidden
-            // take the filename part from the path
-            let filenameonly = PathBuf::from(&filename).file_name().unwrap().to_str().unwrap().to_string();
-            if filenameonly.starts_with(".") {
-                return;
+    fn index_file(&self, filename: &str, force: bool) -> Outcome {
+        let filename = filename.to_string();
+        if self.catalog.is_excluded(&filename) {
+            return Outcome::Skipped(String::from("excluded"));
+        }
+        // don't index if size is greater than 10 MB
+        let metadata = match std::fs::metadata(&filename) {
+            Ok(metadata) => metadata,
+            Err(e) => return Outcome::Failed(e.to_string()),
+        };
+        if metadata.len() > 10 * 1024 * 1024 {
+            self.catalog.set_skipped(&filename, "too_large", &format!("{} bytes", metadata.len()));
+            return Outcome::Skipped(String::from("too_large"));
+        }
+
+        // don't index if file is hidden
+        // take the filename part from the path
+        let filenameonly = PathBuf::from(&filename).file_name().unwrap().to_str().unwrap().to_string();
+        if filenameonly.starts_with(".") {
+            self.catalog.set_skipped(&filename, "hidden", "");
+            return Outcome::Skipped(String::from("hidden"));
+        }
+
+        // skip files whose mtime and size are unchanged since they were
+        // indexed.  If only the mtime changed (e.g. touch), the content hash
+        // tells us whether re-embedding is needed.
+        let (mtime, size) = Self::file_stat(&metadata);
+        let previous = self.indexed_entry(filename.clone());
+        if let Some(ref entry) = previous {
+            if !force && entry.mtime == mtime && entry.size == size {
+                return Outcome::Unchanged;
             }
-            
-            log::debug!("Indexing: {}", filename);
-            let content: Result<String, Box<dyn StdError>> = Parser::new().parse(&filename);
-            if let Err(e) = content {
-                log::debug!("Error: {}", e);
-                return;
+        }
+        let hash = match sha256::try_digest(Path::new(&filename)) {
+            Ok(hash) => hash,
+            Err(e) => {
+                log::debug!("Error hashing {}: {}", filename, e);
+                return Outcome::Failed(e.to_string());
+            }
+        };
+        if let Some(ref entry) = previous {
+            if !force && entry.hash == hash {
+                log::debug!("Content unchanged: {}", filename);
+                let updated = catalog::Entry { indexed_at: entry.indexed_at, metadata: entry.metadata.clone(), ..catalog::Entry::new(filename, entry.indexes.clone(), mtime, size, hash) };
+                self.catalog.add(updated);
+                return Outcome::Unchanged;
+            }
+        } else if let Ok(moved) = self.catalog.get_by_hash(&hash) {
+            // same content is catalogued under a path that no longer exists;
+            // the file was moved or renamed, so carry its vectors over
+            if !force && !Path::new(&moved.filename).exists() {
+                log::debug!("File moved: {} -> {}", moved.filename, filename);
+                let renamed = catalog::Entry { indexed_at: moved.indexed_at, metadata: moved.metadata.clone(), ..catalog::Entry::new(filename, moved.indexes.clone(), mtime, size, hash) };
+                self.catalog.rename(moved.filename.clone(), renamed);
+                return Outcome::Moved(moved.filename);
+            }
+        }
+
+        // the file changed since it was last indexed; drop its old vectors
+        // and catalog keys, so that they don't outlive a file that no longer
+        // parses, before adding the new ones
+        if let Some(entry) = previous {
+            log::debug!("File changed, re-indexing: {}", filename);
+            self.remove_vectors(entry.indexes.clone());
+            self.catalog.delete(filename.clone());
+        }
+
+        log::debug!("Indexing: {}", filename);
+        let document: Result<Document, Box<dyn StdError>> = self.parsers.parse(&filename);
+        if let Err(e) = document {
+            log::debug!("Error: {}", e);
+            let (reason, detail) = match e.downcast_ref::<UnsupportedFileTypeError>() {
+                Some(unsupported) => ("unsupported_mime", unsupported.reason().to_string()),
+                None => ("parse_error", e.to_string()),
+            };
+            self.catalog.set_skipped(&filename, reason, &detail);
+            return Outcome::Skipped(format!("{}: {}", reason, detail));
+        }
+        let document = document.unwrap();
+
+        let ch = channel();
+        self.indexer_channel.send(indexer::Message::AddDocument(document.text(), document.sections.clone(), 0, filename, ch.0)).unwrap();
+        match ch.1.recv() {
+            Ok(indexer::Reply::Done(filename, chunks)) => {
+                log::info!("File indexed: {} ({} chunks)", filename, chunks.len());
+                let ids = chunks.iter().map(|(id, _)| *id).collect::<Vec<u64>>();
+                self.catalog.add_chunks(&chunks);
+                self.catalog.clear_skipped(&filename);
+                self.catalog.add(catalog::Entry { metadata: document.metadata, ..catalog::Entry::new(filename, ids, mtime, size, hash) });
+                Outcome::Indexed(chunks.len())
+            }
+            Ok(indexer::Reply::Failed(e)) => Outcome::Failed(e),
+            _ => Outcome::Failed(String::from("indexer did not respond")),
+        }
+    }
+
+    // Handles an add, remove or reindex request, reporting each file as it is
+    // done and a summary at the end
+    fn admin(&self, msg: Message) {
+        let (tx, summary) = match msg {
+            Message::Add(path, tx) => {
+                // an explicit add undoes an earlier remove
+                self.catalog.include(&path);
+                let mut summary = Summary::default();
+                self.scan_path(path, 0, false, &mut |file, outcome| {
+                    summary.count(&outcome);
+                    let _ = tx.send(Reply::Progress(file.to_string(), outcome));
+                });
+                (tx, summary)
             }
-            let content = content.unwrap();
-            let ch = channel();
-            self.indexer_channel.send(indexer::Message::AddDocument(content, 0, filename, ch.0)).unwrap();
-            if let Ok(rep) = ch.1.recv() {
-                match rep {
-                    indexer::Reply::Done(filename, ids) => {
-                        log::debug!("File indexed: {}", filename);
-                        self.catalog.add(catalog::Entry::new(filename, ids) );
+            Message::Remove(path, tx) => {
+                let mut summary = Summary::default();
+                for file in self.remove_path(Path::new(&path)) {
+                    summary.count(&Outcome::Removed);
+                    let _ = tx.send(Reply::Progress(file, Outcome::Removed));
+                }
+                // keep scans and the watcher from adding it back
+                self.catalog.exclude(&path);
+                (tx, summary)
+            }
+            Message::Reindex(path, tx) => {
+                let mut summary = Summary::default();
+                let mut report = |file: &str, outcome: Outcome| {
+                    summary.count(&outcome);
+                    let _ = tx.send(Reply::Progress(file.to_string(), outcome));
+                };
+                match path {
+                    Some(path) => self.scan_path(path, 0, true, &mut report),
+                    None => {
+                        for entry in self.catalog.entries() {
+                            let outcome = self.index_file(&entry.filename, true);
+                            report(&entry.filename, outcome);
+                        }
                     }
-                    _ => {}
                 }
+                (tx, summary)
             }
-        } else if PathBuf::from(&filename).is_dir() {
-            for entry in std::fs::read_dir(filename).unwrap() {
-                let entry = entry.unwrap();
-                let path = entry.path();
-                let fname = path.to_str().unwrap().to_string();
-                self.scan_file(fname, depth + 1);
+        };
+        let _ = tx.send(Reply::Summary(summary));
+        self.queue.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // Serves admin requests until `period` has passed
+    fn serve_admin(&self, period: Duration) {
+        let deadline = Instant::now() + period;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return;
+            }
+            match self.admin_channel.1.recv_timeout(left) {
+                Ok(msg) => self.admin(msg),
+                Err(_) => return,
             }
         }
     }
+
+    pub fn get_sender(&self) -> Sender<Message> {
+        self.admin_channel.0.clone()
+    }
+
+    pub fn get_queue(&self) -> Arc<AtomicUsize> {
+        self.queue.clone()
+    }
 }
 
 impl Crawler for CrawlerImpl {
     fn new(catalog: Arc<Catalog>, indexer_channel: Sender<indexer::Message>) -> Self {
         let mut obj = CrawlerImpl {
             catalog,
+            parsers: Registry::from_settings(),
             indexer_channel,
+            admin_channel: channel(),
+            queue: Arc::new(AtomicUsize::new(0)),
         };
         obj
     }
 
     fn scan(&self) {
-        let files = settings::get_config(None).files.clone();
-        for filename in files {
-            let mut fname = filename.clone();
-            if fname.starts_with("~") {
-                let home = dirs::home_dir().unwrap();
-                fname = home.to_str().unwrap().to_string() + &filename[1..];
+        self.scan_roots(&Self::roots());
+        self.reconcile();
+    }
+
+    fn reconcile(&self) {
+        // purge catalog entries and vectors of files that were deleted since
+        // they were indexed.  Moved files are already re-keyed by scan_file.
+        let mut purged = 0;
+        for entry in self.catalog.entries() {
+            if Path::new(&entry.filename).exists() {
+                continue;
             }
-            log::debug!("Scanning: {}", fname);
-            if PathBuf::from(&fname).is_dir() {
-                for entry in std::fs::read_dir(fname.clone()).unwrap() {
-                    log::debug!("Dir listing: {:?} {:?}", fname, entry);
-                    let entry = entry.unwrap();
-                    let path = entry.path();
-                    let fname = path.to_str().unwrap().to_string();
-                    self.scan_file(fname, 0);
-                }
-            } else {
-                self.scan_file(fname, 0);
+            log::debug!("File deleted: {}", entry.filename);
+            self.remove_vectors(entry.indexes.clone());
+            self.catalog.delete(entry.filename);
+            purged += 1;
+        }
+        for (filename, _) in self.catalog.skipped() {
+            if !Path::new(&filename).exists() {
+                self.catalog.clear_skipped(&filename);
             }
         }
+        log::info!("Reconciled catalog, purged {} files", purged);
     }
 
     fn run(&mut self) {
+        // watch the configured roots for changes.  Roots that cannot be watched
+        // are picked up by the once a day scan below.
+        let (tx, events) = channel();
+        let mut unwatched = Self::roots();
+        let mut watcher = None;
+        if settings::get_config(None).watch {
+            let (w, rest) = Self::start_watcher(&unwatched, tx);
+            watcher = w;
+            unwatched = rest;
+        }
+        if watcher.is_some() {
+            // catch up with changes made while the daemon was not running
+            log::info!("Scanning...");
+            self.scan();
+        }
+
         // run scan at midnight between 2 to 4 am
         let now = chrono::Local::now();
         let midnight = now.date().and_hms(0, 0, 0);
         let mut next_scan = midnight + chrono::Duration::days(1);
         if now > midnight + chrono::Duration::hours(2) && now < midnight + chrono::Duration::hours(4) {
-            self.scan();
+            self.scan_roots(&unwatched);
+            self.reconcile();
             next_scan = now + chrono::Duration::days(1);
         }
 
@@ -147,12 +562,216 @@ impl Crawler for CrawlerImpl {
             log::info!("Last scan: {}", last_scan_time.to_rfc3339());
             if now > next_scan  || now > last_scan_time + chrono::Duration::days(1){
                 log::info!("Scanning...");
-                self.scan();
+                self.scan_roots(&unwatched);
+                self.reconcile();
                 std::fs::write(last_scan_file, now.to_rfc3339()).unwrap();
                 next_scan = now + chrono::Duration::days(1);
             }
-            thread::sleep(Duration::from_secs(60*5));
+            if watcher.is_some() {
+                self.process_events(&events, Duration::from_secs(60*5));
+            } else {
+                self.serve_admin(Duration::from_secs(60*5));
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::embedder::HashingEmbedder;
+    use crate::idgenerator::IdGenerator;
+    use crate::indexer::{Indexer, IndexerImpl};
+
+    // A crawler with a catalog of its own and an indexer embedding with the
+    // hashing embedder
+    fn crawler() -> (CrawlerImpl, Arc<Catalog>) {
+        settings::init_for_tests();
+        let catalog = Arc::new(Catalog::temporary());
+        let mut indexer = IndexerImpl::new(IdGenerator::new(catalog.clone()), catalog.clone(), Box::new(HashingEmbedder::new(64))).unwrap();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for msg in rx {
+                match msg {
+                    indexer::Message::AddDocument(text, sections, id, loc, reply) => {
+                        let result = match indexer.add_document(text, sections, id, loc.clone()) {
+                            Ok(chunks) => indexer::Reply::Done(loc, chunks),
+                            Err(e) => indexer::Reply::Failed(e.to_string()),
+                        };
+                        let _ = reply.send(result);
+                    }
+                    indexer::Message::RemoveDocument(ids, reply) => {
+                        let _ = reply.send(indexer::Reply::Removed(indexer.remove_document(&ids)));
+                    }
+                    _ => {}
+                }
+            }
+        });
+        (CrawlerImpl::new(catalog.clone(), tx), catalog)
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("semdesk-crawler-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Writes the file with its mtime at `mtime` seconds since the epoch
+    fn write(path: &Path, contents: &[u8], mtime: u64) -> String {
+        std::fs::write(path, contents).unwrap();
+        set_mtime(path, mtime);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn set_mtime(path: &Path, mtime: u64) {
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime)).unwrap();
+    }
+
+    #[test]
+    fn unchanged_file_keeps_its_vectors() {
+        let (crawler, catalog) = crawler();
+        let file = write(&test_dir("unchanged").join("fox.txt"), b"The quick brown fox jumps over the lazy dog.", 1_000_000);
+        assert!(matches!(crawler.index_file(&file, false), Outcome::Indexed(1)));
+        let indexed = catalog.get_by_file(file.clone()).unwrap();
+
+        assert!(matches!(crawler.index_file(&file, false), Outcome::Unchanged));
+        let entry = catalog.get_by_file(file.clone()).unwrap();
+        assert_eq!(entry.indexes, indexed.indexes);
+        assert_eq!(entry.indexed_at, indexed.indexed_at);
+        assert_eq!(catalog.vector_count(), 1);
+    }
+
+    #[test]
+    fn touched_file_is_checked_by_hash() {
+        let (crawler, catalog) = crawler();
+        let path = test_dir("touched").join("fox.txt");
+        let file = write(&path, b"The quick brown fox jumps over the lazy dog.", 1_000_000);
+        crawler.index_file(&file, false);
+        let indexed = catalog.get_by_file(file.clone()).unwrap();
+
+        set_mtime(&path, 2_000_000);
+        assert!(matches!(crawler.index_file(&file, false), Outcome::Unchanged));
+        let entry = catalog.get_by_file(file.clone()).unwrap();
+        assert_eq!(entry.indexes, indexed.indexes);
+        assert_eq!(entry.mtime, 2_000_000);
+        assert_eq!(entry.hash, indexed.hash);
+        assert_eq!(catalog.vector_count(), 1);
+    }
+
+    #[test]
+    fn changed_file_is_reindexed() {
+        let (crawler, catalog) = crawler();
+        let path = test_dir("changed").join("notes.txt");
+        let file = write(&path, b"The quick brown fox jumps over the lazy dog.", 1_000_000);
+        crawler.index_file(&file, false);
+        let indexed = catalog.get_by_file(file.clone()).unwrap();
+
+        write(&path, b"Green tea is brewed with water below boiling.", 2_000_000);
+        assert!(matches!(crawler.index_file(&file, false), Outcome::Indexed(1)));
+        let entry = catalog.get_by_file(file.clone()).unwrap();
+        assert_ne!(entry.hash, indexed.hash);
+        assert_eq!(entry.mtime, 2_000_000);
+        assert!(entry.indexes.iter().all(|id| !indexed.indexes.contains(id)));
+        assert!(catalog.get_chunk(indexed.indexes[0]).is_err());
+        assert_eq!(catalog.get_chunk(entry.indexes[0]).unwrap().text.trim(), "Green tea is brewed with water below boiling.");
+        assert_eq!(catalog.vector_count(), 1);
+    }
+
+    #[test]
+    fn changed_file_that_no_longer_parses_loses_its_vectors() {
+        let (crawler, catalog) = crawler();
+        let path = test_dir("unparsable").join("notes.txt");
+        let file = write(&path, b"The quick brown fox jumps over the lazy dog.", 1_000_000);
+        crawler.index_file(&file, false);
+        let indexed = catalog.get_by_file(file.clone()).unwrap();
+
+        // text files must be UTF-8
+        write(&path, b"The quick brown \xff\xfe fox", 2_000_000);
+        match crawler.index_file(&file, false) {
+            Outcome::Skipped(reason) => assert!(reason.starts_with("parse_error: "), "{}", reason),
+            outcome => panic!("expected the file to be skipped, got {:?}", outcome),
         }
+        assert!(catalog.get_by_file(file.clone()).is_err());
+        assert!(catalog.get_chunk(indexed.indexes[0]).is_err());
+        assert!(catalog.search_lexical("fox", 10).is_empty());
+        assert_eq!(catalog.vector_count(), 0);
+        assert!(catalog.get_skipped(&file).is_some());
     }
+    #[test]
+    fn moved_file_keeps_its_vectors() {
+        let (crawler, catalog) = crawler();
+        let dir = test_dir("moved");
+        let from = write(&dir.join("fox.txt"), b"The quick brown fox jumps over the lazy dog.", 1_000_000);
+        crawler.index_file(&from, false);
+        let indexed = catalog.get_by_file(from.clone()).unwrap();
 
+        let to = dir.join("animals.txt").to_str().unwrap().to_string();
+        std::fs::rename(&from, &to).unwrap();
+        match crawler.index_file(&to, false) {
+            Outcome::Moved(moved) => assert_eq!(moved, from),
+            outcome => panic!("expected the file to be moved, got {:?}", outcome),
+        }
+        assert!(catalog.get_by_file(from.clone()).is_err());
+        let entry = catalog.get_by_file(to.clone()).unwrap();
+        assert_eq!(entry.indexes, indexed.indexes);
+        assert_eq!(catalog.get_by_hash(&indexed.hash).unwrap().filename, to);
+        assert!(catalog.get_chunk(indexed.indexes[0]).is_ok());
+        assert_eq!(catalog.vector_count(), 1);
+    }
+
+    #[test]
+    fn copy_of_a_file_is_not_a_move() {
+        let (crawler, catalog) = crawler();
+        let dir = test_dir("copied");
+        let original = write(&dir.join("fox.txt"), b"The quick brown fox jumps over the lazy dog.", 1_000_000);
+        crawler.index_file(&original, false);
+        let indexed = catalog.get_by_file(original.clone()).unwrap();
+
+        let copy = write(&dir.join("copy.txt"), b"The quick brown fox jumps over the lazy dog.", 1_000_000);
+        assert!(matches!(crawler.index_file(&copy, false), Outcome::Indexed(1)));
+        assert_eq!(catalog.get_by_file(original.clone()).unwrap().indexes, indexed.indexes);
+        let entry = catalog.get_by_file(copy.clone()).unwrap();
+        assert_eq!(entry.hash, indexed.hash);
+        assert!(entry.indexes.iter().all(|id| !indexed.indexes.contains(id)));
+        assert_eq!(catalog.vector_count(), 2);
+    }
+
+    #[test]
+    fn removes_a_directory_but_not_its_siblings() {
+        let (crawler, catalog) = crawler();
+        let dir = test_dir("removed");
+        std::fs::create_dir_all(dir.join("notes")).unwrap();
+        std::fs::create_dir_all(dir.join("notes2")).unwrap();
+        let inside = write(&dir.join("notes").join("fox.txt"), b"The quick brown fox jumps over the lazy dog.", 1_000_000);
+        let sibling = write(&dir.join("notes2").join("tea.txt"), b"Green tea is brewed with water below boiling.", 1_000_000);
+        crawler.index_file(&inside, false);
+        crawler.index_file(&sibling, false);
+
+        let removed = crawler.remove_path(&dir.join("notes/"));
+        assert_eq!(removed, vec![inside.clone()]);
+        assert!(catalog.get_by_file(inside).is_err());
+        assert!(catalog.get_by_file(sibling).is_ok());
+        assert_eq!(catalog.vector_count(), 1);
+    }
+    #[test]
+    fn scans_past_missing_and_unreadable_roots() {
+        use std::os::unix::fs::PermissionsExt;
+        let (crawler, catalog) = crawler();
+        let dir = test_dir("roots");
+        let file = write(&dir.join("fox.txt"), b"The quick brown fox jumps over the lazy dog.", 1_000_000);
+        let missing = dir.join("gone").to_str().unwrap().to_string();
+        let unreadable = dir.join("locked");
+        std::fs::create_dir_all(&unreadable).unwrap();
+        std::fs::set_permissions(&unreadable, std::fs::Permissions::from_mode(0o000)).unwrap();
+
+        crawler.scan_roots(&vec![missing, unreadable.to_str().unwrap().to_string(), dir.to_str().unwrap().to_string()]);
+        std::fs::set_permissions(&unreadable, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(catalog.get_by_file(file).is_ok());
+    }
 }
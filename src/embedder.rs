@@ -0,0 +1,201 @@
+// Copyright: (c) 2023 Sureshkumar T
+// License: Apache-2.0
+
+use std::error::Error;
+use std::path::PathBuf;
+use rust_bert::pipelines::sentence_embeddings::SentenceEmbeddingsBuilder;
+use rust_bert::pipelines::sentence_embeddings::SentenceEmbeddingsModelType;
+use rust_bert::pipelines::sentence_embeddings::SentenceEmbeddingsModel;
+
+use crate::settings;
+use crate::error;
+
+pub trait Embedder: Send {
+    // length of the vectors returned by embed
+    fn dimension(&self) -> usize;
+    fn model_id(&self) -> String;
+    fn embed(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, Box<dyn Error>>;
+}
+
+pub struct BertEmbedder {
+    model: SentenceEmbeddingsModel,
+    model_id: String,
+    dimension: usize,
+}
+
+impl BertEmbedder {
+    // Downloads the model on first use and caches it under ~/.cache/.rustbert
+    pub fn remote(model_type: SentenceEmbeddingsModelType) -> Result<Self, Box<dyn Error>> {
+        let model_id = format!("{:?}", model_type);
+        let model = SentenceEmbeddingsBuilder::remote(model_type).create_model()?;
+        Self::with_model(model, model_id)
+    }
+
+    // Loads a sentence-transformers model converted for rust-bert from a
+    // directory holding modules.json, config.json, rust_model.ot and the
+    // tokenizer files.
+    pub fn local(model_dir: &str) -> Result<Self, Box<dyn Error>> {
+        let model = SentenceEmbeddingsBuilder::local(PathBuf::from(model_dir)).create_model()?;
+        Self::with_model(model, format!("local:{}", model_dir))
+    }
+
+    fn with_model(model: SentenceEmbeddingsModel, model_id: String) -> Result<Self, Box<dyn Error>> {
+        // the dimension is not part of the model config, so ask the model
+        let probe = model.encode(&["semdesk"])?;
+        let dimension = probe[0].len();
+        log::info!("Loaded embedding model {} ({} dimensions)", model_id, dimension);
+        Ok(BertEmbedder { model, model_id, dimension })
+    }
+}
+
+impl Embedder for BertEmbedder {
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> String {
+        self.model_id.clone()
+    }
+
+    fn embed(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, Box<dyn Error>> {
+        Ok(self.model.encode(texts)?)
+    }
+}
+
+// Embeds text by hashing its words and word pairs into buckets.  There is no
+// notion of meaning, only of shared vocabulary, but it needs no model and the
+// same text always gives the same vector, which is what tests need.
+pub struct HashingEmbedder {
+    dimension: usize,
+}
+
+impl HashingEmbedder {
+    pub fn new(dimension: usize) -> Self {
+        HashingEmbedder { dimension }
+    }
+
+    // FNV-1a, as std's hasher is not guaranteed stable across releases
+    fn hash(text: &str) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for b in text.as_bytes() {
+            hash ^= *b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
+
+    fn add_feature(&self, vector: &mut Vec<f32>, feature: &str) {
+        let hash = Self::hash(feature);
+        let bucket = (hash % self.dimension as u64) as usize;
+        let sign = if (hash >> 63) == 0 { 1.0 } else { -1.0 };
+        vector[bucket] += sign;
+    }
+}
+
+impl Embedder for HashingEmbedder {
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn model_id(&self) -> String {
+        format!("hashing-{}", self.dimension)
+    }
+
+    fn embed(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>, Box<dyn Error>> {
+        let mut vectors = Vec::new();
+        for text in texts {
+            let words = text.split(|c: char| !c.is_alphanumeric())
+                .filter(|w| w.len() > 0)
+                .map(|w| w.to_lowercase())
+                .collect::<Vec<String>>();
+            let mut vector = vec![0.0; self.dimension];
+            for i in 0..words.len() {
+                self.add_feature(&mut vector, &words[i]);
+                if i + 1 < words.len() {
+                    self.add_feature(&mut vector, &format!("{} {}", words[i], words[i + 1]));
+                }
+            }
+            vectors.push(vector);
+        }
+        Ok(vectors)
+    }
+}
+
+// Checks that a local model directory has the files BertEmbedder::local
+// reads, so that a bad path fails at startup rather than with a panic deep in
+// rust-bert.
+pub fn validate_local(model_dir: &str) -> Result<(), error::Error> {
+    let dir = PathBuf::from(model_dir);
+    if !dir.is_dir() {
+        return Err(error::Error::new(&format!("embedding model directory {} does not exist", model_dir)));
+    }
+    let mut missing = Vec::new();
+    for file in ["modules.json", "config.json", "rust_model.ot", "tokenizer_config.json", "sentence_bert_config.json"] {
+        if !dir.join(file).exists() {
+            missing.push(file);
+        }
+    }
+    // bert models ship vocab.txt, roberta vocab.json and albert/t5 a sentencepiece model
+    if !["vocab.txt", "vocab.json", "spiece.model"].iter().any(|f| dir.join(f).exists()) {
+        missing.push("vocab.txt");
+    }
+    if missing.len() > 0 {
+        return Err(error::Error::new(&format!("embedding model directory {} is missing {}", model_dir, missing.join(", "))));
+    }
+    Ok(())
+}
+
+// Creates the embedder selected by `model.embedder` in the config, loading
+// the model from `model.embedding_path` when it is set
+pub fn from_settings() -> Result<Box<dyn Embedder>, Box<dyn Error>> {
+    let conf = settings::get_config(None);
+    match conf.embedder.as_str() {
+        "hashing" => Ok(Box::new(HashingEmbedder::new(384))),
+        "bert" => match settings::get_embedding_path() {
+            Some(path) => Ok(Box::new(BertEmbedder::local(&path)?)),
+            None => Ok(Box::new(BertEmbedder::remote(SentenceEmbeddingsModelType::AllMiniLmL6V2)?)),
+        },
+        other => Err(Box::new(error::Error::new(&format!("unknown model.embedder {}, expected bert or hashing", other)))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::catalog::Catalog;
+    use crate::chunker::Section;
+    use crate::idgenerator::IdGenerator;
+    use crate::indexer::{Indexer, IndexerImpl, SearchParams};
+
+    #[test]
+    fn hashing_embedder_is_deterministic() {
+        let embedder = HashingEmbedder::new(64);
+        let a = embedder.embed(&["The quick brown fox"]).unwrap();
+        let b = embedder.embed(&["the QUICK brown fox!"]).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a[0].len(), 64);
+    }
+
+    #[test]
+    fn indexes_and_retrieves_with_hashing_embedder() {
+        settings::init_for_tests();
+        let catalog = Arc::new(Catalog::temporary());
+        let embedder = Box::new(HashingEmbedder::new(64));
+        let mut indexer = IndexerImpl::new(IdGenerator::new(catalog.clone()), catalog.clone(), embedder).unwrap();
+
+        let fox = indexer.add_document(String::from("The quick brown fox jumps over the lazy dog."),
+                                       vec![Section { start: 0, headings: vec![] }], 0, String::from("fox.txt")).unwrap();
+        let tea = indexer.add_document(String::from("Green tea is brewed with water below boiling."),
+                                       vec![Section { start: 0, headings: vec![] }], 0, String::from("tea.txt")).unwrap();
+        assert_eq!(fox.len(), 1);
+        assert_eq!(tea.len(), 1);
+
+        let params = SearchParams { top_k: 2, min_score: 0.0, nprobe: None, ef_search: None };
+        let hits = indexer.retrieve_document("quick brown fox", &params).unwrap();
+        assert_eq!(hits[0].0, fox[0].0);
+        let hits = indexer.retrieve_document("brewed green tea", &params).unwrap();
+        assert_eq!(hits[0].0, tea[0].0);
+    }
+}
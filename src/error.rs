@@ -55,17 +55,24 @@ impl StdError for FileNotFoundError {
 #[derive(Debug)]
 pub struct UnsupportedFileTypeError {
     filename: String,
+    reason: String,
 }
 impl UnsupportedFileTypeError {
-    pub fn new(filename: &str) -> Self {
+    pub fn new(filename: &str, reason: &str) -> Self {
         UnsupportedFileTypeError {
             filename: filename.to_string(),
+            reason: reason.to_string(),
         }
     }
+
+    // why no parser could be used for the file
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
 }
 impl Display for UnsupportedFileTypeError {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        write!(f, "UnsupportedFileTypeError: {}", self.filename)
+        write!(f, "UnsupportedFileTypeError: {}: {}", self.filename, self.reason)
     }
 }
 impl StdError for UnsupportedFileTypeError {
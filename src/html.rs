@@ -0,0 +1,421 @@
+// Copyright: (c) 2023 Sureshkumar T
+// License: Apache-2.0
+
+// Parser for web pages, as .html files or saved as single file .mhtml
+// archives.  Only the readable content of a page is kept: scripts, styles,
+// navigation, headers and footers are dropped, and when the page marks its
+// main content only that is read.  Headings become sections of the document
+// like those of markup files.  The title, description and canonical url of
+// the page go into its metadata.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+
+use base64::Engine;
+use scraper::{ElementRef, Html, Node, Selector};
+
+use crate::error;
+use crate::markup::Outline;
+use crate::parsers::{Document, DocumentParser};
+
+// Elements whose content is never part of the readable text
+const SKIPPED: [&str; 15] = [
+    "script", "style", "noscript", "template", "head", "nav", "aside",
+    "form", "button", "select", "svg", "canvas", "iframe", "object", "dialog",
+];
+
+// Page headers and footers, which are only chrome when reading the whole body.
+// Those of an article hold its title and byline.
+const CHROME: [&str; 2] = ["header", "footer"];
+
+// Landmark roles of page chrome
+const SKIPPED_ROLES: [&str; 6] = ["navigation", "banner", "contentinfo", "complementary", "search", "menu"];
+
+// Elements that start a new line
+const BLOCKS: [&str; 25] = [
+    "p", "div", "section", "article", "main", "blockquote", "pre", "ul", "ol", "li", "dl", "dt", "dd",
+    "table", "thead", "tbody", "tfoot", "tr", "figure", "figcaption", "address", "details", "summary", "hr", "br",
+];
+
+fn select<'a>(html: &'a Html, selector: &str) -> Option<ElementRef<'a>> {
+    html.select(&Selector::parse(selector).unwrap()).next()
+}
+
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+// Walks the elements of a page writing their text to the outline
+struct Reader {
+    outline: Outline,
+    line: String,
+    // depth of <pre> elements the walk is in, whose whitespace is kept
+    pre: usize,
+    // whether the page marks no main content, so the whole body is read
+    body: bool,
+}
+
+impl Reader {
+    fn flush(&mut self) {
+        if self.line.trim().len() > 0 {
+            let line = std::mem::take(&mut self.line);
+            self.outline.line(&line);
+        }
+        self.line.clear();
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.pre > 0 {
+            for (i, part) in text.split('\n').enumerate() {
+                if i > 0 {
+                    let line = std::mem::take(&mut self.line);
+                    self.outline.line(&line);
+                }
+                self.line.push_str(part);
+            }
+            return;
+        }
+        let collapsed = collapse(text);
+        if collapsed.len() == 0 {
+            if text.len() > 0 && !self.line.ends_with(' ') && self.line.len() > 0 {
+                self.line.push(' ');
+            }
+            return;
+        }
+        if text.starts_with(char::is_whitespace) && self.line.len() > 0 && !self.line.ends_with(' ') {
+            self.line.push(' ');
+        }
+        self.line.push_str(&collapsed);
+        if text.ends_with(char::is_whitespace) {
+            self.line.push(' ');
+        }
+    }
+
+    fn skipped(&self, element: &scraper::node::Element) -> bool {
+        if SKIPPED.contains(&element.name()) || element.attr("hidden").is_some() {
+            return true;
+        }
+        if self.body && CHROME.contains(&element.name()) {
+            return true;
+        }
+        if element.attr("aria-hidden") == Some("true") {
+            return true;
+        }
+        match element.attr("role") {
+            Some(role) => SKIPPED_ROLES.contains(&role),
+            None => false,
+        }
+    }
+
+    fn walk(&mut self, element: ElementRef) {
+        for child in element.children() {
+            match child.value() {
+                Node::Text(text) => self.text(text),
+                Node::Element(e) => {
+                    if self.skipped(e) {
+                        continue;
+                    }
+                    let child = ElementRef::wrap(child).unwrap();
+                    let name = e.name();
+                    let level = match name {
+                        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => name[1..].parse::<usize>().ok(),
+                        _ => None,
+                    };
+                    if let Some(level) = level {
+                        self.flush();
+                        self.outline.heading(level, &collapse(&child.text().collect::<String>()));
+                        continue;
+                    }
+                    let block = BLOCKS.contains(&name);
+                    if block {
+                        self.flush();
+                    }
+                    if name == "pre" {
+                        self.pre += 1;
+                    }
+                    if name == "img" {
+                        if let Some(alt) = e.attr("alt") {
+                            self.text(alt);
+                        }
+                    }
+                    self.walk(child);
+                    if name == "pre" {
+                        self.pre -= 1;
+                    }
+                    match name {
+                        "td" | "th" => self.line.push_str("  "),
+                        _ if block => self.flush(),
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// The element holding the main content of the page: the one marked as such,
+// or the longest article, or else the whole body
+fn main_content(html: &Html) -> Option<ElementRef> {
+    if let Some(main) = select(html, "main, [role=main]") {
+        return Some(main);
+    }
+    let articles = Selector::parse("article").unwrap();
+    let longest = html.select(&articles)
+        .max_by_key(|a| a.text().map(|t| t.trim().len()).sum::<usize>());
+    longest.or_else(|| select(html, "body"))
+}
+
+fn meta(html: &Html, selector: &str, attribute: &str) -> Option<String> {
+    select(html, selector)
+        .and_then(|e| e.value().attr(attribute).map(collapse))
+        .filter(|v| v.len() > 0)
+}
+
+pub fn parse_html(source: &str) -> Document {
+    let html = Html::parse_document(source);
+    let mut metadata = BTreeMap::new();
+    let title = select(&html, "title").map(|t| collapse(&t.text().collect::<String>()))
+        .filter(|t| t.len() > 0)
+        .or_else(|| meta(&html, "meta[property='og:title']", "content"));
+    if let Some(title) = title {
+        metadata.insert(String::from("title"), title);
+    }
+    let description = meta(&html, "meta[name=description]", "content")
+        .or_else(|| meta(&html, "meta[property='og:description']", "content"));
+    if let Some(description) = description {
+        metadata.insert(String::from("description"), description);
+    }
+    let url = meta(&html, "link[rel=canonical]", "href")
+        .or_else(|| meta(&html, "meta[property='og:url']", "content"));
+    if let Some(url) = url {
+        metadata.insert(String::from("url"), url);
+    }
+    if let Some(author) = meta(&html, "meta[name=author]", "content") {
+        metadata.insert(String::from("author"), author);
+    }
+
+    let mut reader = Reader { outline: Outline::new(), line: String::new(), pre: 0, body: false };
+    if let Some(content) = main_content(&html) {
+        reader.body = content.value().name() == "body";
+        reader.walk(content);
+    }
+    reader.flush();
+    reader.outline.into_document(metadata)
+}
+
+// Headers of a MIME message or part, up to the blank line after them, with
+// continuation lines unfolded.  Returns them with names in lower case, and
+// the rest of the text.
+fn mime_headers(text: &str) -> (Vec<(String, String)>, &str) {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        offset += line.len();
+        let line = line.trim_end_matches(|c| c == '\r' || c == '\n');
+        if line.len() == 0 {
+            if headers.len() > 0 {
+                break;
+            }
+            continue;
+        }
+        if line.starts_with(char::is_whitespace) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+    }
+    (headers, &text[offset.min(text.len())..])
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+}
+
+// A parameter of a header value, e.g. the boundary of
+// multipart/related; boundary="----abc"
+fn header_parameter(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1)
+        .filter_map(|p| p.split_once('='))
+        .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.trim().trim_matches('"').to_string())
+}
+
+fn decode_quoted_printable(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'=' {
+            // soft line break
+            if bytes[i + 1..].starts_with(b"\r\n") {
+                i += 3;
+                continue;
+            }
+            if bytes[i + 1..].starts_with(b"\n") {
+                i += 2;
+                continue;
+            }
+            let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+            if let Some(byte) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    out
+}
+
+// The html of the page a .mhtml archive was saved from, and the url it was
+// saved from
+fn mhtml_page(text: &str) -> Result<(String, Option<String>), Box<dyn Error>> {
+    let (headers, body) = mime_headers(text);
+    let location = header(&headers, "snapshot-content-location")
+        .or_else(|| header(&headers, "content-location"))
+        .map(|l| l.to_string());
+    let boundary = header(&headers, "content-type").and_then(|t| header_parameter(t, "boundary"))
+        .ok_or_else(|| error::Error::new("mhtml archive without a multipart boundary"))?;
+    let delimiter = format!("--{}", boundary);
+    for part in body.split(delimiter.as_str()).skip(1) {
+        if part.starts_with("--") {
+            break;
+        }
+        let (headers, content) = mime_headers(part);
+        let is_html = header(&headers, "content-type")
+            .map(|t| t.to_lowercase().starts_with("text/html"))
+            .unwrap_or(false);
+        if !is_html {
+            continue;
+        }
+        let encoding = header(&headers, "content-transfer-encoding").unwrap_or("8bit").to_lowercase();
+        let bytes = match encoding.as_str() {
+            "quoted-printable" => decode_quoted_printable(content),
+            "base64" => {
+                let encoded = content.split_whitespace().collect::<String>();
+                base64::engine::general_purpose::STANDARD.decode(encoded)?
+            }
+            _ => content.as_bytes().to_vec(),
+        };
+        let location = location.or_else(|| header(&headers, "content-location").map(|l| l.to_string()));
+        return Ok((String::from_utf8_lossy(&bytes).into_owned(), location));
+    }
+    Err(Box::new(error::Error::new("mhtml archive without an html part")))
+}
+
+pub struct HtmlParser;
+
+impl DocumentParser for HtmlParser {
+    fn name(&self) -> &'static str {
+        "html"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["text/html", "application/xhtml+xml", "multipart/related", "application/x-mimearchive"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["html", "htm", "xhtml", "mhtml", "mht"]
+    }
+
+    fn magic(&self) -> &'static [&'static [u8]] {
+        &[b"<!DOCTYPE html", b"<!doctype html", b"<html"]
+    }
+
+    fn parse(&self, filename: &str) -> Result<Document, Box<dyn Error>> {
+        let text = String::from_utf8_lossy(&fs::read(filename)?).into_owned();
+        let (headers, _) = mime_headers(&text);
+        let archive = header(&headers, "content-type")
+            .map(|t| t.to_lowercase().starts_with("multipart/"))
+            .unwrap_or(false);
+        if archive {
+            let (page, location) = mhtml_page(&text)?;
+            let mut document = parse_html(&page);
+            if let Some(location) = location {
+                document.metadata.entry(String::from("url")).or_insert(location);
+            }
+            return Ok(document);
+        }
+        Ok(parse_html(&text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_main_content_and_metadata() {
+        let document = parse_html(r#"<!DOCTYPE html><html><head>
+            <title> Release   notes </title>
+            <meta name="description" content="What changed">
+            <link rel="canonical" href="https://example.com/notes">
+            <script>var tracking = 1;</script>
+            </head><body>
+            <nav><a href="/">Home</a></nav>
+            <main>
+              <h1>Version 2</h1>
+              <p>Faster <b>search</b>.</p>
+              <div aria-hidden="true">Hidden</div>
+              <h2>Fixes</h2>
+              <ul><li>One</li><li>Two</li></ul>
+              <img src="chart.png" alt="Speed chart">
+            </main>
+            <footer>Copyright</footer>
+            </body></html>"#);
+        assert_eq!(document.metadata.get("title").map(String::as_str), Some("Release notes"));
+        assert_eq!(document.metadata.get("description").map(String::as_str), Some("What changed"));
+        assert_eq!(document.metadata.get("url").map(String::as_str), Some("https://example.com/notes"));
+        let text = document.text();
+        assert!(text.contains("Faster search."));
+        assert!(text.contains("One\nTwo\n"));
+        assert!(text.contains("Speed chart"));
+        for chrome in ["Home", "tracking", "Hidden", "Copyright"] {
+            assert!(!text.contains(chrome), "{} in {:?}", chrome, text);
+        }
+        let headings = document.sections.iter().map(|s| s.headings.join(" > ")).collect::<Vec<String>>();
+        assert_eq!(headings, vec!["Version 2", "Version 2 > Fixes"]);
+    }
+
+    #[test]
+    fn reads_whole_body_without_main() {
+        let document = parse_html("<html><body><header>Site</header><p>Only  paragraph</p><footer>Legal</footer></body></html>");
+        assert_eq!(document.text().trim(), "Only paragraph");
+    }
+
+    #[test]
+    fn decodes_quoted_printable() {
+        assert_eq!(decode_quoted_printable("caf=C3=A9 soft=\r\nbreak =3D x="), "café softbreak = x=".as_bytes());
+    }
+
+    #[test]
+    fn finds_page_in_quoted_printable_mhtml() {
+        let archive = "From: <Saved by Blink>\r\nSnapshot-Content-Location: https://example.com/page\r\nContent-Type: multipart/related;\r\n\ttype=\"text/html\"; boundary=\"----abc\"\r\n\r\n------abc\r\nContent-Type: text/html\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\n<p class=3D\"x\">caf=C3=A9</p>\r\n------abc\r\nContent-Type: image/png\r\n\r\nPNG\r\n------abc--\r\n";
+        let (page, location) = mhtml_page(archive).unwrap();
+        assert!(page.contains("<p class=\"x\">café</p>"));
+        assert_eq!(location.as_deref(), Some("https://example.com/page"));
+    }
+
+    #[test]
+    fn finds_page_in_base64_mhtml() {
+        let encoded = base64::engine::general_purpose::STANDARD.encode("<h1>Saved</h1>");
+        let archive = format!("Content-Type: multipart/related; boundary=b\n\n--b\nContent-Type: text/html; charset=utf-8\nContent-Location: https://example.com/\nContent-Transfer-Encoding: base64\n\n{}\n--b--\n", encoded);
+        let (page, location) = mhtml_page(&archive).unwrap();
+        assert_eq!(page.trim(), "<h1>Saved</h1>");
+        assert_eq!(location.as_deref(), Some("https://example.com/"));
+    }
+
+    #[test]
+    fn rejects_mhtml_without_html() {
+        assert!(mhtml_page("Content-Type: text/plain\n\nhello\n").is_err());
+        assert!(mhtml_page("Content-Type: multipart/related; boundary=b\n\n--b\nContent-Type: image/png\n\nPNG\n--b--\n").is_err());
+    }
+}
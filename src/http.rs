@@ -0,0 +1,323 @@
+// Copyright: (c) 2023 Sureshkumar T
+// License: Apache-2.0
+
+// Optional HTTP/JSON API on localhost for tools that can't talk to the unix
+// socket.  It takes the same requests as the socket and answers with the same
+// responses, as newline delimited JSON streamed one response per line.  Every
+// request needs the token from <config dir>/http_token in an
+// `Authorization: Bearer <token>` header.
+//
+//   POST /v1/request                 any socket protocol request
+//   POST /v1/search                  {"query": "...", "options": {...}}
+//   GET  /v1/documents/<id>          contents of the file holding chunk <id>
+//   GET  /v1/documents?path=<path>   contents of a catalogued file
+//   GET  /v1/status                  what the daemon has indexed
+//   GET  /v1/files?prefix=<prefix>   catalogued and skipped files
+//   GET  /v1/file?path=<path>        one file with its chunks
+//   POST /v1/admin/add               {"path": "..."} index a file or directory now
+//   POST /v1/admin/remove            {"path": "..."} drop it from the index
+//   POST /v1/admin/reindex           {"path": "..."} re-embed it, or everything
+
+use std::error::Error as StdError;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+use serde::Deserialize;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::error::Error;
+use crate::protocol;
+use crate::server::{Client, Worker};
+use crate::settings;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+pub struct HttpServer {
+    server: Arc<tiny_http::Server>,
+    token: Arc<String>,
+    worker: Worker,
+}
+
+#[derive(Deserialize)]
+struct PathBody {
+    #[serde(default)]
+    path: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SearchBody {
+    query: String,
+    #[serde(default)]
+    options: protocol::QueryOptions,
+}
+
+// Streams responses as the chunks of a chunked transfer encoded body, so
+// each one reaches the client as soon as it is sent
+struct HttpClient {
+    writer: Box<dyn Write + Send>,
+    started: bool,
+}
+
+impl HttpClient {
+    fn write_chunk(&mut self, data: &[u8]) -> std::io::Result<()> {
+        write!(self.writer, "{:x}\r\n", data.len())?;
+        self.writer.write_all(data)?;
+        self.writer.write_all(b"\r\n")?;
+        self.writer.flush()
+    }
+
+    fn finish(&mut self) {
+        if self.started {
+            let _ = self.writer.write_all(b"0\r\n\r\n").and_then(|_| self.writer.flush());
+        }
+    }
+}
+
+impl Client for HttpClient {
+    fn send(&mut self, response: &protocol::Response) -> Result<(), Box<dyn StdError>> {
+        if !self.started {
+            self.writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nTransfer-Encoding: chunked\r\n\r\n")?;
+            self.started = true;
+        }
+        let mut line = serde_json::to_vec(response)?;
+        line.push(b'\n');
+        self.write_chunk(&line)?;
+        Ok(())
+    }
+
+    // a request that failed before anything was sent gets a 500 with the
+    // error as its body, like the errors of respond_error
+    fn fail(&mut self, response: &protocol::Response) -> Result<(), Box<dyn StdError>> {
+        if self.started {
+            return self.send(response);
+        }
+        let body = serde_json::to_vec(response)?;
+        write!(self.writer, "HTTP/1.1 500 Internal Server Error\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n", body.len())?;
+        self.writer.write_all(&body)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    // a disconnect is noticed when the next response can't be written
+    fn gone(&mut self) -> bool {
+        false
+    }
+}
+
+// Reads the API token, creating a random one readable only by the user on
+// first start
+fn load_token() -> Result<String, Box<dyn StdError>> {
+    let path = settings::get_http_token_file();
+    if let Ok(token) = std::fs::read_to_string(&path) {
+        let token = token.trim().to_string();
+        if token.len() == 0 {
+            return Err(Box::new(Error::new(&format!("http token file {} is empty", path))));
+        }
+        return Ok(token);
+    }
+    let token: String = rand::thread_rng().sample_iter(&Alphanumeric).take(40).map(char::from).collect();
+    let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+    file.write_all(token.as_bytes())?;
+    log::info!("Generated http api token in {}", path);
+    Ok(token)
+}
+
+// Compares without bailing out at the first difference
+fn token_matches(given: &str, token: &str) -> bool {
+    if given.len() != token.len() {
+        return false;
+    }
+    given.bytes().zip(token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn authorized(request: &Request, token: &str) -> bool {
+    request.headers().iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+        .map(|given| token_matches(given.trim(), token))
+        .unwrap_or(false)
+}
+
+fn respond_error(request: Request, status: u16, message: &str) {
+    let body = serde_json::to_string(&protocol::Response::error("", message)).unwrap();
+    let response = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap());
+    if let Err(e) = request.respond(response) {
+        log::debug!("Error writing http response: {}", e);
+    }
+}
+
+fn next_id() -> String {
+    format!("http-{}", NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+fn read_body(request: &mut Request) -> Result<String, Box<dyn StdError>> {
+    let mut body = String::new();
+    request.as_reader().take(protocol::MAX_REQUEST_BYTES).read_to_string(&mut body)?;
+    Ok(body)
+}
+
+// Maps the route to a socket protocol request
+fn parse_request(request: &mut Request) -> Result<protocol::Request, (u16, String)> {
+    let url = request.url().to_string();
+    let (path, query) = match url.split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (url.clone(), String::new()),
+    };
+    let method = request.method().clone();
+    match (method, path.as_str()) {
+        (Method::Post, "/v1/request") => {
+            let body = read_body(request).map_err(|e| (400, e.to_string()))?;
+            protocol::parse_request(&body).map_err(|e| (400, format!("invalid request: {}", e)))
+        }
+        (Method::Post, "/v1/search") => {
+            let body = read_body(request).map_err(|e| (400, e.to_string()))?;
+            let search: SearchBody = serde_json::from_str(&body).map_err(|e| (400, format!("invalid request: {}", e)))?;
+            Ok(protocol::Request::new(next_id(), protocol::RequestBody::Query { query: search.query, options: search.options }))
+        }
+        (Method::Get, "/v1/documents") => {
+            let doc_path = form_urlencoded::parse(query.as_bytes())
+                .find(|(k, _)| k == "path")
+                .map(|(_, v)| v.to_string())
+                .ok_or((400, String::from("path is required")))?;
+            Ok(protocol::Request::new(next_id(), protocol::RequestBody::Document { chunk_id: None, path: Some(doc_path) }))
+        }
+        (Method::Post, p) if p.starts_with("/v1/admin/") => {
+            let body = read_body(request).map_err(|e| (400, e.to_string()))?;
+            let body: PathBody = serde_json::from_str(&body).map_err(|e| (400, format!("invalid request: {}", e)))?;
+            let admin = match (&p["/v1/admin/".len()..], body.path) {
+                ("add", Some(path)) => protocol::RequestBody::Add { path },
+                ("remove", Some(path)) => protocol::RequestBody::Remove { path },
+                ("reindex", path) => protocol::RequestBody::Reindex { path },
+                ("add", None) | ("remove", None) => return Err((400, String::from("path is required"))),
+                _ => return Err((404, String::from("not found"))),
+            };
+            Ok(protocol::Request::new(next_id(), admin))
+        }
+        (Method::Get, "/v1/files") => {
+            let prefix = form_urlencoded::parse(query.as_bytes())
+                .find(|(k, _)| k == "prefix")
+                .map(|(_, v)| v.to_string());
+            Ok(protocol::Request::new(next_id(), protocol::RequestBody::List { prefix }))
+        }
+        (Method::Get, "/v1/file") => {
+            let file_path = form_urlencoded::parse(query.as_bytes())
+                .find(|(k, _)| k == "path")
+                .map(|(_, v)| v.to_string())
+                .ok_or((400, String::from("path is required")))?;
+            Ok(protocol::Request::new(next_id(), protocol::RequestBody::Show { path: file_path }))
+        }
+        (Method::Get, "/v1/status") => Ok(protocol::Request::new(next_id(), protocol::RequestBody::Status)),
+        (Method::Get, p) if p.starts_with("/v1/documents/") => {
+            let id = p["/v1/documents/".len()..].parse::<u64>().map_err(|_| (400, String::from("invalid document id")))?;
+            Ok(protocol::Request::new(next_id(), protocol::RequestBody::Document { chunk_id: Some(id), path: None }))
+        }
+        _ => Err((404, String::from("not found"))),
+    }
+}
+
+fn handle_request(worker: &mut Worker, token: &str, mut request: Request) {
+    if !authorized(&request, token) {
+        return respond_error(request, 401, "missing or invalid token");
+    }
+    let parsed = match parse_request(&mut request) {
+        Ok(parsed) => parsed,
+        Err((status, message)) => return respond_error(request, status, &message),
+    };
+    let mut client = HttpClient { writer: request.into_writer(), started: false };
+    if let Err(e) = worker.handle(parsed, &mut client) {
+        log::debug!("Http request cancelled: {}", e);
+        return;
+    }
+    client.finish();
+}
+
+impl HttpServer {
+    pub fn new(worker: Worker) -> Result<Self, Box<dyn StdError>> {
+        let token = load_token()?;
+        let addr = format!("127.0.0.1:{}", settings::get_config(None).http_port);
+        let server = tiny_http::Server::http(&addr).map_err(|e| Error::new(&format!("cannot listen on {}: {}", addr, e)))?;
+        log::info!("Http api listening on {}", addr);
+        Ok(HttpServer {
+            server: Arc::new(server),
+            token: Arc::new(token),
+            worker,
+        })
+    }
+
+    pub fn run(&mut self) {
+        let mut threads = Vec::new();
+        for _ in 0..settings::get_config(None).http_workers {
+            let server = self.server.clone();
+            let token = self.token.clone();
+            let mut worker = self.worker.clone();
+            threads.push(thread::spawn(move || loop {
+                match server.recv() {
+                    Ok(request) => handle_request(&mut worker, &token, request),
+                    Err(e) => log::error!("Error accepting http request: {}", e),
+                }
+            }));
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::catalog::Catalog;
+    use crate::retriever;
+
+    // What the client wrote, readable after the writer is handed over
+    #[derive(Clone)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_search_is_a_server_error() {
+        settings::init_for_tests();
+        let (retriever, requests) = channel();
+        thread::spawn(move || {
+            while let Ok(retriever::Message::RetrieveByQuery(_, _, tx)) = requests.recv() {
+                let _ = tx.send(retriever::Reply::Failed(String::from("search failed: index is corrupt")));
+            }
+        });
+        let mut worker = Worker::new(Arc::new(Catalog::temporary()), channel().0, Arc::new(AtomicUsize::new(0)), channel().0, retriever, channel().0);
+        let buffer = Buffer(Arc::new(Mutex::new(Vec::new())));
+        let mut client = HttpClient { writer: Box::new(buffer.clone()), started: false };
+        let request = protocol::Request::new(String::from("http-1"), protocol::RequestBody::Query { query: String::from("where is it"), options: protocol::QueryOptions::default() });
+        worker.handle(request, &mut client).unwrap();
+        client.finish();
+
+        let written = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let (head, body) = written.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 500 "), "{}", head);
+        assert!(head.contains(&format!("Content-Length: {}", body.len())), "{}", head);
+        let response: protocol::Response = serde_json::from_str(body).unwrap();
+        match response.body {
+            protocol::ResponseBody::Error { message } => assert_eq!(message, "search failed: index is corrupt"),
+            body => panic!("expected an error, got {:?}", body),
+        }
+    }
+}
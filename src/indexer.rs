@@ -6,12 +6,13 @@ use faiss::{ index_factory, MetricType};
 use faiss::index::IndexImpl;
 use faiss::Index;
 use faiss;
-use rust_bert::pipelines::sentence_embeddings::SentenceEmbeddingsBuilder;
-use rust_bert::pipelines::sentence_embeddings::SentenceEmbeddingsModelType;
-use rust_bert::pipelines::sentence_embeddings::SentenceEmbeddingsModel;
 use sha256;
 use faiss::Idx;
-use std::collections::HashMap;
+use faiss::selector::IdSelector;
+use faiss::index::autotune::ParameterSpace;
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
 use std::fmt::Debug;
 use std::sync::mpsc::{Sender, Receiver, channel};
 use std::sync::Arc;
@@ -21,8 +22,11 @@ use std::time::Duration;
 use std::thread;
 
 use crate::settings;
+use crate::error;
 use crate::idgenerator::IdGenerator;
 use crate::catalog::Catalog;
+use crate::chunker::{Chunk, Chunker, Section};
+use crate::embedder::Embedder;
 
 
 #[link(name = "faiss")]
@@ -37,55 +41,247 @@ pub fn renorm_L2(d: usize, nx: usize, x: *mut f32) {
     }
 }
 
+// How many vectors a search returns and how hard it looks for them
+#[derive(Debug, Clone)]
+pub struct SearchParams {
+    pub top_k: usize,
+    // hits with a lower inner product with the query are dropped
+    pub min_score: f32,
+    // number of IVF lists and size of the HNSW candidate list searched
+    pub nprobe: Option<u32>,
+    pub ef_search: Option<u32>,
+}
+
+impl Default for SearchParams {
+    fn default() -> Self {
+        let conf = settings::get_config(None);
+        SearchParams {
+            top_k: conf.top_k,
+            min_score: conf.min_score,
+            nprobe: conf.nprobe,
+            ef_search: conf.ef_search,
+        }
+    }
+}
+
+// Which faiss index the vectors are in, saved in the catalog next to the
+// index file.  `trained_at` is the number of vectors the index was trained
+// on, zero for indexes that need no training.
+#[derive(Serialize, Deserialize, Debug)]
+struct IndexMeta {
+    factory: String,
+    trained_at: u64,
+}
+
+// Index used until there are enough vectors to train the configured index
+const STAGING_FACTORY: &str = "IDMap,Flat";
+
+// Most vectors one search returns.  faiss allocates room for all of them.
+pub const MAX_TOP_K: usize = 1000;
+
 #[derive(Debug)]
 pub enum Message {
-    AddDocument(String, u64, String, Sender<Reply>),
-    RetrieveDocument(String, Sender<Reply>),
+    AddDocument(String, Vec<Section>, u64, String, Sender<Reply>),
+    RemoveDocument(Vec<u64>, Sender<Reply>),
+    RetrieveDocument(String, SearchParams, Sender<Reply>),
+    Status(Sender<Reply>),
 }
 
 pub enum Reply {
-    Done(String, Vec<u64>),
+    Done(String, Vec<(u64, Chunk)>),
+    Removed(usize),
     Docs(Vec<(u64, f32)>),
+    Status(IndexStatus),
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexStatus {
+    // vectors in the faiss index
+    pub ntotal: u64,
+    pub factory: String,
+    // documents waiting to be indexed or removed
+    pub queue_depth: usize,
+    pub model_id: String,
 }
 
 pub trait Indexer {
-    fn add_document(&mut self, document: String, docid: u64, loc: String) -> Vec<u64>;
-    fn retrieve_document(&mut self, query: &str) -> Vec<(u64, f32)>;
+    fn add_document(&mut self, document: String, sections: Vec<Section>, docid: u64, loc: String) -> Result<Vec<(u64, Chunk)>, Box<dyn Error>>;
+    fn remove_document(&mut self, ids: &[u64]) -> usize;
+    fn retrieve_document(&mut self, query: &str, params: &SearchParams) -> Result<Vec<(u64, f32)>, Box<dyn Error>>;
     fn run(&mut self);
 }
 
 
 pub struct IndexerImpl {
     index: IndexImpl,
-    model: SentenceEmbeddingsModel,
-    token_size: usize,
+    // factory string of `index` and the number of vectors it was trained on
+    factory: String,
+    trained_at: u64,
+    // whether the configured index has to be trained before it takes vectors
+    target_needs_training: bool,
+    // factory and vector count of the last rebuild that failed, which isn't
+    // retried until either changes
+    failed_rebuild: Option<(String, u64)>,
+    catalog: Arc<Catalog>,
+    embedder: Box<dyn Embedder>,
+    chunker: Chunker,
+    dimension: usize,
     adder_channel: (Sender<Message>, Receiver<Message>),
     retriever_channel: (Sender<Message>, Receiver<Message>),
+    // adds and removals received but not yet processed
+    backlog: VecDeque<Message>,
     id_gen: IdGenerator,
     muted: bool,
 }
 
 impl IndexerImpl {
-    pub fn new(id_gen: IdGenerator) -> Self {
-        let token_size = 384;
-        let mut index = index_factory(token_size, "IDMap,Flat", MetricType::InnerProduct).unwrap();
-        let model = SentenceEmbeddingsBuilder::remote(
-             SentenceEmbeddingsModelType::AllMiniLmL6V2,
-         ).create_model().unwrap();
+    // Fails if the configured index factory is invalid or the saved index
+    // can't be used with the embedder
+    pub fn new(id_gen: IdGenerator, catalog: Arc<Catalog>, embedder: Box<dyn Embedder>) -> Result<Self, Box<dyn Error>> {
+        let dimension = embedder.dimension() as u32;
+        let target = settings::get_config(None).index_factory.clone();
+        let target_needs_training = Self::needs_training(dimension, &target)?;
+        let mut factory = target.clone();
+        if target_needs_training {
+            factory = String::from(STAGING_FACTORY);
+        }
+        let mut trained_at = 0;
+        let mut index = index_factory(dimension, &factory, MetricType::InnerProduct)?;
         let index_location = settings::get_index_location();
         if std::path::Path::new(&index_location).exists() {
             log::debug!("Loading index from {}", index_location);
-            index = faiss::read_index(&index_location).unwrap();
+            index = faiss::read_index(&index_location)
+                .map_err(|e| error::Error::new(&format!("cannot read index {}: {}", index_location, e)))?;
             log::debug!("Loaded index {}", index.ntotal());
+            if index.d() != dimension {
+                return Err(Box::new(error::Error::new(&format!("index at {} has {} dimensions but model {} produces {}, remove the index and db to re-index",
+                                                               index_location, index.d(), embedder.model_id(), dimension))));
+            }
+            // indexes written before the factory was configurable are flat
+            let meta = catalog.get_meta("index")
+                .and_then(|m| serde_json::from_str::<IndexMeta>(&m).ok())
+                .unwrap_or(IndexMeta { factory: String::from(STAGING_FACTORY), trained_at: 0 });
+            factory = meta.factory;
+            trained_at = meta.trained_at;
         }
-        IndexerImpl {
+        let mut obj = IndexerImpl {
             index,
-            model,
-            token_size: token_size as usize,
+            factory,
+            trained_at,
+            target_needs_training,
+            failed_rebuild: None,
+            catalog,
+            embedder,
+            chunker: Chunker::new(),
+            dimension: dimension as usize,
             adder_channel: channel(),
             retriever_channel: channel(),
+            backlog: VecDeque::new(),
             id_gen,
             muted: false,
+        };
+        obj.maybe_rebuild();
+        Ok(obj)
+    }
+
+    // Whether an index made from the factory string has to be trained before
+    // it takes vectors.  Fails if faiss doesn't understand the string.
+    fn needs_training(dimension: u32, factory: &str) -> Result<bool, Box<dyn Error>> {
+        match index_factory(dimension, factory, MetricType::InnerProduct) {
+            Ok(index) => Ok(!index.is_trained()),
+            Err(e) => Err(Box::new(error::Error::new(&format!("invalid index.factory {}: {}", factory, e)))),
+        }
+    }
+
+    // Switches to the configured index once there are enough vectors to
+    // train it, when the configuration changed, or when the collection has
+    // grown enough since training that the clustering is likely stale.
+    fn maybe_rebuild(&mut self) {
+        let conf = settings::get_config(None);
+        let target = conf.index_factory.clone();
+        let ntotal = self.index.ntotal();
+        if self.failed_rebuild == Some((target.clone(), ntotal)) {
+            return;
+        }
+        if self.factory != target {
+            if !self.target_needs_training || ntotal >= conf.train_min_vectors {
+                self.rebuild(&target);
+            }
+        } else if self.trained_at > 0 && ntotal as f64 >= self.trained_at as f64 * conf.retrain_growth {
+            self.rebuild(&target);
+        }
+    }
+
+    fn rebuild(&mut self, factory: &str) {
+        self.failed_rebuild = Some((factory.to_string(), self.index.ntotal()));
+        let stored = self.catalog.vector_count() as u64;
+        if stored < self.index.ntotal() {
+            log::warn!("Only {} of {} vectors are stored, re-index to switch to index {}",
+                       stored, self.index.ntotal(), factory);
+            return;
+        }
+        let vectors = self.catalog.vectors();
+        log::info!("Building index {} from {} vectors", factory, vectors.len());
+        let mut index = index_factory(self.dimension as u32, factory, MetricType::InnerProduct).unwrap();
+        let mut trained_at = 0;
+        if !index.is_trained() {
+            // train on an evenly spread sample of at most train_max_vectors
+            let step = (vectors.len() / settings::get_config(None).train_max_vectors).max(1);
+            let sample = vectors.iter().step_by(step).flat_map(|(_, v)| v.iter().cloned()).collect::<Vec<f32>>();
+            if let Err(e) = index.train(&sample) {
+                log::error!("Training index {} failed: {}", factory, e);
+                return;
+            }
+            trained_at = vectors.len() as u64;
+        }
+        for batch in vectors.chunks(1024) {
+            let data = batch.iter().flat_map(|(_, v)| v.iter().cloned()).collect::<Vec<f32>>();
+            let ids = batch.iter().map(|(id, _)| Idx::new(*id)).collect::<Vec<Idx>>();
+            if let Err(e) = index.add_with_ids(&data, &ids) {
+                log::error!("Building index {} failed: {}", factory, e);
+                return;
+            }
+        }
+        self.index = index;
+        self.factory = factory.to_string();
+        self.trained_at = trained_at;
+        self.failed_rebuild = None;
+        self.muted = true;
+        log::info!("Built index {} with {} vectors", factory, self.index.ntotal());
+    }
+
+    fn set_search_params(&mut self, params: &SearchParams) {
+        let space = ParameterSpace::new().unwrap();
+        // parameters that don't apply to the index type are rejected by faiss
+        if let Some(nprobe) = params.nprobe {
+            if let Err(e) = space.set_index_parameter(&self.index, "nprobe", nprobe) {
+                log::debug!("Cannot set nprobe on {}: {}", self.factory, e);
+            }
+        }
+        if let Some(ef_search) = params.ef_search {
+            if let Err(e) = space.set_index_parameter(&self.index, "efSearch", ef_search) {
+                log::debug!("Cannot set efSearch on {}: {}", self.factory, e);
+            }
+        }
+    }
+
+    // Number of chunks to embed at a time.  The activations of one chunk take
+    // roughly chunk_tokens * dimension * 4 bytes per layer and attention head,
+    // which we round up to 64 copies.
+    fn batch_size(&self) -> usize {
+        let conf = settings::get_config(None);
+        let per_chunk = conf.chunk_tokens * self.dimension * 4 * 64;
+        let fits = conf.embed_memory_mb * 1024 * 1024 / per_chunk.max(1);
+        fits.min(conf.embed_batch_size).max(1)
+    }
+
+    fn status(&self) -> IndexStatus {
+        IndexStatus {
+            ntotal: self.index.ntotal(),
+            factory: self.factory.clone(),
+            queue_depth: self.backlog.len(),
+            model_id: self.embedder.model_id(),
         }
     }
 
@@ -100,63 +296,85 @@ impl IndexerImpl {
 }
 
 impl Indexer for IndexerImpl {
-    fn add_document(&mut self, document: String, docid1:u64, loc: String) -> Vec<u64> {
-        // chunk the document into self.token_size byte chunks with padding if less
-        let chunks = document.as_bytes().chunks(self.token_size).map(|c| 
-                                         String::from_utf8(c.to_vec())
-                                         .unwrap_or(String::from(""))
-                                         ).collect::<Vec<String>>();
-        let mut input = Vec::new();
-        for chunk in chunks.iter() {
-            let chunk = chunk.trim();
-            if chunk.len() > 0 {
-                input.push(chunk);
-            }
-        }
+    fn add_document(&mut self, document: String, sections: Vec<Section>, docid1:u64, loc: String) -> Result<Vec<(u64, Chunk)>, Box<dyn Error>> {
+        // chunk the document on sentence boundaries within the model's token budget
+        let chunks = self.chunker.chunk_sections(&document, &sections)
+            .into_iter()
+            .filter(|c| c.text.trim().len() > 0)
+            .collect::<Vec<Chunk>>();
+        let input = chunks.iter().map(|c| c.text.trim()).collect::<Vec<&str>>();
         if chunks.len() == 0 || input.len() == 0 {
             log::debug!("No input for document {}", loc);
-            return Vec::new();
+            return Ok(Vec::new());
         }
-        println!("Input size {} Chunk size {}", input.len(), chunks.len());
-        // chunks of length greater than  ~50 takes a hell lot of memory if we give all of them at
-        // one go.  We can throttle with few sentences each time. But that increases cpu time.
-        // Hence, let's truncate a large file at loss of information
-        if input.len() > 50 {
-            input.truncate(50);
+        let batch_size = self.batch_size();
+        log::debug!("Input size {} Chunk size {} Batch size {}", input.len(), chunks.len(), batch_size);
+        // encoding many chunks at one go takes a lot of memory, so embed the
+        // document a batch at a time.  Nothing is added to the index until
+        // all of it is embedded, so a failure leaves no stray vectors.
+        let mut tokens = Vec::new();
+        for batch in input.chunks(batch_size) {
+            tokens.extend(self.embedder.embed(batch)?);
         }
-        let tokens = self.model.encode(&input).unwrap();
         let mut ids = Vec::new();
-        for i in 0..tokens.len() {
-            let mut token = tokens[i].clone();
-            log::debug!("Token size {}/{}", token.len(), self.token_size);
-            if token.len() < self.token_size {
+        for mut token in tokens {
+            log::debug!("Token size {}/{}", token.len(), self.dimension);
+            if token.len() < self.dimension {
                 log::debug!("Resizing");
-                token.resize(self.token_size, 0.0);
+                token.resize(self.dimension, 0.0);
             }
-            renorm_L2(self.token_size, 1, token.as_mut_ptr());
+            renorm_L2(self.dimension, 1, token.as_mut_ptr());
             let docid = self.id_gen.next();
-            ids.push(docid);
+            ids.push((docid, chunks[ids.len()].clone()));
             log::debug!("Adding document {} ", docid);
             let idx = Idx::new(docid);
             self.index.add_with_ids(&token, &[idx]).unwrap();
+            self.catalog.add_vector(docid, &token);
             self.muted = true;
         }
+        self.maybe_rebuild();
         log::debug!("Done indexing document {} ", loc);
-        ids
+        Ok(ids)
     }
-    fn retrieve_document(&mut self, query: &str) -> Vec<(u64, f32)> {
+    fn remove_document(&mut self, ids: &[u64]) -> usize {
+        if ids.len() == 0 {
+            return 0;
+        }
+        let idxs = ids.iter().map(|id| Idx::new(*id)).collect::<Vec<Idx>>();
+        let selector = IdSelector::batch(&idxs).unwrap();
+        for id in ids {
+            self.catalog.remove_vector(*id);
+        }
+        // not every index type supports removal (e.g. HNSW); the stale vectors
+        // then stay until the next rebuild, and their hits are dropped by the
+        // retriever as they are no longer in the catalog
+        let removed = match self.index.remove_ids(&selector) {
+            Ok(removed) => removed,
+            Err(e) => {
+                log::warn!("Cannot remove vectors from {}: {}", self.factory, e);
+                0
+            }
+        };
+        log::debug!("Removed {}/{} vectors", removed, ids.len());
+        if removed > 0 {
+            self.muted = true;
+        }
+        removed
+    }
+    fn retrieve_document(&mut self, query: &str, params: &SearchParams) -> Result<Vec<(u64, f32)>, Box<dyn Error>> {
         log::debug!("Query {} total_indexes: {}", query, self.index.ntotal());
-        let tokens = self.model.encode(&[String::from(query)]).unwrap();
+        let tokens = self.embedder.embed(&[query])?;
         let mut token = tokens[0].clone();
-        if tokens.len() < self.token_size {
-                token.resize(self.token_size, 0.0);
+        if token.len() < self.dimension {
+                token.resize(self.dimension, 0.0);
         }
-        renorm_L2(self.token_size, 1, token.as_mut_ptr());
-        let res = self.index.search(&token, 6).unwrap();
+        renorm_L2(self.dimension, 1, token.as_mut_ptr());
+        self.set_search_params(params);
+        let res = self.index.search(&token, params.top_k.clamp(1, MAX_TOP_K))?;
         let mut docs: Vec<(u64, f32)> = Vec::new();
         for (dist, label) in res.distances.iter().zip(res.labels.iter()) {
             log::debug!("Dist {} Label {}", dist, label);
-            if dist < &0.10 {
+            if *dist < params.min_score {
                 continue;
             }
             let id = label;
@@ -165,28 +383,51 @@ impl Indexer for IndexerImpl {
             }
             docs.push((id.get().unwrap(), dist.clone()));
         }
-        docs
+        Ok(docs)
     }
     fn run(&mut self) {
         let mut counter = 0;
         loop {
             while let Ok(msg) = self.retriever_channel.1.try_recv() {
                 match msg {
-                    Message::RetrieveDocument(query, tx) => {
+                    Message::RetrieveDocument(query, params, tx) => {
                         log::debug!("Retrieving document {} ", query);
-                        let docs = self.retrieve_document(&query);
-                        tx.send(Reply::Docs(docs)).unwrap();
+                        let reply = match self.retrieve_document(&query, &params) {
+                            Ok(docs) => Reply::Docs(docs),
+                            Err(e) => {
+                                log::error!("Search for {} failed: {}", query, e);
+                                Reply::Failed(format!("search failed: {}", e))
+                            }
+                        };
+                        let _ = tx.send(reply);
+                    }
+                    Message::Status(tx) => {
+                        let _ = tx.send(Reply::Status(self.status()));
                     }
                     _ => { break; }
                 }
             }
 
-            if let Ok(msg) = self.adder_channel.1.try_recv() {
+            while let Ok(msg) = self.adder_channel.1.try_recv() {
+                self.backlog.push_back(msg);
+            }
+            if let Some(msg) = self.backlog.pop_front() {
                 match msg {
-                    Message::AddDocument(doc, id, loc, tx) => {
+                    Message::AddDocument(doc, sections, id, loc, tx) => {
                         log::debug!("Received Indexing document {} ", loc);
-                        let ids = self.add_document(doc, id, loc.clone());
-                        tx.send(Reply::Done(loc.clone(), ids)).unwrap();
+                        let reply = match self.add_document(doc, sections, id, loc.clone()) {
+                            Ok(ids) => Reply::Done(loc, ids),
+                            Err(e) => {
+                                log::error!("Indexing {} failed: {}", loc, e);
+                                Reply::Failed(format!("embedding failed: {}", e))
+                            }
+                        };
+                        let _ = tx.send(reply);
+                    }
+                    Message::RemoveDocument(ids, tx) => {
+                        log::debug!("Received removal of {} vectors", ids.len());
+                        let removed = self.remove_document(&ids);
+                        tx.send(Reply::Removed(removed)).unwrap();
                     }
                     _ => {}
                 }
@@ -206,6 +447,8 @@ impl Indexer for IndexerImpl {
                 }
 
                 faiss::write_index(&self.index, index_location).unwrap();
+                let meta = IndexMeta { factory: self.factory.clone(), trained_at: self.trained_at };
+                self.catalog.set_meta("index", &serde_json::to_string(&meta).unwrap());
 
                 counter = 0;
                 self.muted = false;
@@ -217,3 +460,16 @@ impl Indexer for IndexerImpl {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_the_factory_string() {
+        assert!(!IndexerImpl::needs_training(64, "IDMap,Flat").unwrap());
+        assert!(IndexerImpl::needs_training(64, "IVF4,Flat").unwrap());
+        let e = IndexerImpl::needs_training(64, "NoSuchIndex").unwrap_err();
+        assert!(e.to_string().contains("invalid index.factory NoSuchIndex"), "{}", e);
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright: (c) 2023 Sureshkumar T
+// License: Apache-2.0
+
+use sled;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+// BM25 parameters, the usual defaults
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+
+// Inverted index over chunk text for exact term matches (identifiers, error
+// codes, names) that the embedding model doesn't capture well.  Stored in its
+// own sled tree:
+//   t/<term>\0<id>  -> term frequency in the chunk
+//   d/<id>          -> chunk length and terms, to remove the postings again
+//   stats           -> number of chunks and their total length
+pub struct LexicalIndex {
+    tree: sled::Tree,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Stats {
+    docs: u64,
+    total_len: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DocInfo {
+    len: u32,
+    terms: Vec<String>,
+}
+
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|t| t.len() > 0)
+        .map(|t| t.to_lowercase())
+        .collect()
+}
+
+fn posting_prefix(term: &str) -> Vec<u8> {
+    let mut key = format!("t/{}", term).into_bytes();
+    key.push(0);
+    key
+}
+
+fn posting_key(term: &str, id: u64) -> Vec<u8> {
+    let mut key = posting_prefix(term);
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+impl LexicalIndex {
+    pub fn new(tree: sled::Tree) -> Self {
+        LexicalIndex { tree }
+    }
+
+    fn stats(&self) -> Stats {
+        match self.tree.get("stats").unwrap() {
+            Some(v) => serde_json::from_slice(v.as_ref()).unwrap_or_default(),
+            None => Stats::default(),
+        }
+    }
+
+    fn set_stats(&self, stats: &Stats) {
+        self.tree.insert("stats", serde_json::to_vec(stats).unwrap()).unwrap();
+    }
+
+    pub fn add(&self, id: u64, text: &str) {
+        let tokens = tokenize(text);
+        let mut tf: HashMap<String, u32> = HashMap::new();
+        for token in tokens.iter() {
+            *tf.entry(token.clone()).or_insert(0) += 1;
+        }
+        for (term, count) in tf.iter() {
+            self.tree.insert(posting_key(term, id), &count.to_be_bytes()).unwrap();
+        }
+        let info = DocInfo { len: tokens.len() as u32, terms: tf.into_keys().collect() };
+        self.tree.insert(format!("d/{}", id), serde_json::to_vec(&info).unwrap()).unwrap();
+
+        let mut stats = self.stats();
+        stats.docs += 1;
+        stats.total_len += info.len as u64;
+        self.set_stats(&stats);
+    }
+
+    pub fn remove(&self, id: u64) {
+        let info: DocInfo = match self.tree.remove(format!("d/{}", id)).unwrap() {
+            Some(v) => serde_json::from_slice(v.as_ref()).unwrap(),
+            None => return,
+        };
+        for term in info.terms.iter() {
+            self.tree.remove(posting_key(term, id)).unwrap();
+        }
+        let mut stats = self.stats();
+        stats.docs = stats.docs.saturating_sub(1);
+        stats.total_len = stats.total_len.saturating_sub(info.len as u64);
+        self.set_stats(&stats);
+    }
+
+    fn doc_len(&self, id: u64) -> u32 {
+        match self.tree.get(format!("d/{}", id)).unwrap() {
+            Some(v) => serde_json::from_slice::<DocInfo>(v.as_ref()).map(|d| d.len).unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    // Returns up to k chunk ids with their BM25 score, best first
+    pub fn search(&self, query: &str, k: usize) -> Vec<(u64, f32)> {
+        let stats = self.stats();
+        if stats.docs == 0 {
+            return Vec::new();
+        }
+        let n = stats.docs as f32;
+        let avg_len = stats.total_len as f32 / n;
+
+        let mut terms = tokenize(query);
+        terms.sort();
+        terms.dedup();
+
+        let mut scores: HashMap<u64, f32> = HashMap::new();
+        let mut lens: HashMap<u64, f32> = HashMap::new();
+        for term in terms.iter() {
+            let prefix = posting_prefix(term);
+            let postings = self.tree.scan_prefix(&prefix)
+                .map(|kv| kv.unwrap())
+                .map(|(k, v)| {
+                    let id = u64::from_be_bytes(k[prefix.len()..].try_into().unwrap());
+                    let tf = u32::from_be_bytes(v.as_ref().try_into().unwrap());
+                    (id, tf as f32)
+                })
+                .collect::<Vec<(u64, f32)>>();
+            if postings.len() == 0 {
+                continue;
+            }
+            let df = postings.len() as f32;
+            let idf = ((n - df + 0.5) / (df + 0.5) + 1.0).ln();
+            for (id, tf) in postings {
+                let len = *lens.entry(id).or_insert_with(|| self.doc_len(id) as f32);
+                let score = idf * tf * (K1 + 1.0) / (tf + K1 * (1.0 - B + B * len / avg_len));
+                *scores.entry(id).or_insert(0.0) += score;
+            }
+        }
+
+        let mut ranked = scores.into_iter().collect::<Vec<(u64, f32)>>();
+        ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        ranked.truncate(k);
+        ranked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(texts: &[&str]) -> LexicalIndex {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let index = LexicalIndex::new(db.open_tree("lexical").unwrap());
+        for (i, text) in texts.iter().enumerate() {
+            index.add(i as u64 + 1, text);
+        }
+        index
+    }
+
+    #[test]
+    fn tokenizes_on_punctuation() {
+        assert_eq!(tokenize("Call read_line(), got E1042!"), vec!["call", "read_line", "got", "e1042"]);
+    }
+
+    #[test]
+    fn ranks_rare_terms_first() {
+        let index = index(&[
+            "error in the parser",
+            "error in the loader, error again",
+            "error in the indexer: SLED_CORRUPT",
+        ]);
+        let ranked = index.search("error sled_corrupt", 10);
+        assert_eq!(ranked.len(), 3);
+        assert_eq!(ranked[0].0, 3);
+        assert!(ranked.windows(2).all(|w| w[0].1 >= w[1].1));
+        assert_eq!(index.search("error", 2).len(), 2);
+        assert!(index.search("missing", 10).is_empty());
+    }
+
+    #[test]
+    fn removal_deletes_postings() {
+        let index = index(&["the quick brown fox", "the lazy dog"]);
+        index.remove(1);
+        assert!(index.search("fox", 10).is_empty());
+        let ranked = index.search("the", 10);
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].0, 2);
+        assert_eq!(index.tree.scan_prefix(posting_prefix("quick")).count(), 0);
+        assert!(index.tree.get("d/1").unwrap().is_none());
+        let stats = index.stats();
+        assert_eq!((stats.docs, stats.total_len), (1, 3));
+
+        // removing it again changes nothing
+        index.remove(1);
+        assert_eq!(index.stats().docs, 1);
+    }
+}
@@ -4,11 +4,20 @@
 mod settings;
 mod crawler;
 mod indexer;
+mod chunker;
+mod embedder;
 mod idgenerator;
 mod query_processor;
 mod retriever;
 mod catalog;
+mod lexical;
 mod parsers;
+mod markup;
+mod html;
+mod office;
+mod protocol;
+mod server;
+mod http;
 mod error;
 
 
@@ -36,26 +45,43 @@ use indexer::{Indexer, IndexerImpl};
 use settings::get_config;
 use crawler::{Crawler, CrawlerImpl};
 use query_processor::{QueryProcessor, QueryProcessorImpl};
+use server::Server;
+use http::HttpServer;
 use retriever::{Retriever, RetrieverImpl};
 use catalog::Catalog;
 
-fn warmup() {
+fn warmup() -> Result<(), Box<dyn std::error::Error>> {
+    // refuse to start with model paths that don't hold a usable model rather
+    // than falling back to downloading one
+    if let Some(path) = settings::get_embedding_path() {
+        embedder::validate_local(&path)?;
+        tracing::info!("embedding model found at {}", path);
+    }
+    if let Some(path) = settings::get_qa_path() {
+        query_processor::validate_qa_model(&path)?;
+        tracing::info!("question answering model found at {}", path);
+    }
+
     // remove unix socket file
     let _ = std::fs::remove_file(settings::get_socket_path());
     let _ = std::fs::create_dir_all(settings::get_config_dir());
-    // check for binary runtime dependencies
+    // check for binary runtime dependencies.  Ghostscript is only used for
+    // pdfs the native extractor can't read.
     let binaries = vec!["pdf2ps", "ps2ascii"];
-    for bin in binaries {
-        let bin_path = which::which(bin);
-        match bin_path {
-            Ok(_) => {
-                tracing::info!("{} found", bin);
-            },
-            Err(_) => {
-                tracing::warn!("{} not found", bin);
+    if settings::get_config(None).pdf_fallback == "ghostscript" {
+        for bin in binaries {
+            let bin_path = which::which(bin);
+            match bin_path {
+                Ok(_) => {
+                    tracing::info!("{} found", bin);
+                },
+                Err(_) => {
+                    tracing::warn!("{} not found, pdfs that fail to parse natively will be skipped", bin);
+                }
             }
         }
     }
+    Ok(())
 }
 
 #[derive(Parser, Debug)]
@@ -93,15 +119,44 @@ fn main() {
         }
     }
 
-    warmup();
+    if let Err(e) = warmup() {
+        tracing::error!("{}", e);
+        std::process::exit(1);
+    }
 
     let catalog = Catalog::new();
     let arc_catalog = Arc::new(catalog);
 
-    let mut indexer = IndexerImpl::new(idgenerator::IdGenerator::new(arc_catalog.clone()));
+    let embedder = match embedder::from_settings() {
+        Ok(embedder) => embedder,
+        Err(e) => {
+            tracing::error!("Cannot load the embedding model: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // a model that is there but can't be loaded is only found out here
+    let mut query_processor: QueryProcessorImpl = match QueryProcessor::new() {
+        Ok(query_processor) => query_processor,
+        Err(e) => {
+            tracing::error!("Cannot load the question answering model: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut indexer = match IndexerImpl::new(idgenerator::IdGenerator::new(arc_catalog.clone()), arc_catalog.clone(), embedder) {
+        Ok(indexer) => indexer,
+        Err(e) => {
+            tracing::error!("Cannot open the index: {}", e);
+            std::process::exit(1);
+        }
+    };
     let idx_adder_ch = indexer.get_adder();
     let idx_query_ch = indexer.get_retriever();
+    let idx_status_ch = indexer.get_retriever();
     let mut file_crawler = CrawlerImpl::new(arc_catalog.clone(), idx_adder_ch);
+    let crawler_ch = file_crawler.get_sender();
+    let crawler_queue = file_crawler.get_queue();
 
     let thr1 = thread::spawn(move || {
         file_crawler.run();
@@ -118,14 +173,33 @@ fn main() {
         retriever_obj.run();
     });
 
-    let mut query_processor: QueryProcessorImpl = QueryProcessor::new(retriever_ch);
     let query_ch = query_processor.get_query_channel();
     let thr3 = thread::spawn(move || {
         query_processor.run();
     });
 
+    let worker = server::Worker::new(arc_catalog.clone(), crawler_ch, crawler_queue, idx_status_ch, retriever_ch, query_ch);
+
+    // the http api is opt in and the daemon runs without it if it can't start
+    if settings::get_config(None).http_enabled {
+        match HttpServer::new(worker.clone()) {
+            Ok(mut http_server) => {
+                thread::spawn(move || {
+                    http_server.run();
+                });
+            }
+            Err(e) => tracing::error!("Http api not started: {}", e),
+        }
+    }
+
+    let mut server = Server::new(worker);
+    let thr5 = thread::spawn(move || {
+        server.run();
+    });
+
     thr1.join().unwrap();
     thr2.join().unwrap();
     thr3.join().unwrap();
     thr4.join().unwrap();
+    thr5.join().unwrap();
 }
@@ -0,0 +1,667 @@
+// Copyright: (c) 2023 Sureshkumar T
+// License: Apache-2.0
+
+// Parsers for plain text markup: Markdown, reStructuredText and Org mode.
+// Markup is stripped so only the readable text is embedded, headings are kept
+// as the sections of the document, and front matter or document keywords go
+// into its metadata.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+
+use pulldown_cmark::{Event, Options, Tag};
+
+use crate::chunker::Section;
+use crate::parsers::{Document, DocumentParser};
+
+// Builds the text of a document line by line, keeping track of the headings
+// it is under
+pub(crate) struct Outline {
+    text: String,
+    headings: Vec<(usize, String)>,
+    sections: Vec<Section>,
+}
+
+impl Outline {
+    pub(crate) fn new() -> Self {
+        Outline { text: String::new(), headings: Vec::new(), sections: Vec::new() }
+    }
+
+    // Starts a section at the current end of the text.  Levels start at 1
+    // for the outermost headings.
+    pub(crate) fn heading(&mut self, level: usize, title: &str) {
+        let title = title.trim();
+        if title.len() == 0 {
+            return;
+        }
+        while self.headings.last().map(|(l, _)| *l >= level).unwrap_or(false) {
+            self.headings.pop();
+        }
+        self.headings.push((level, title.to_string()));
+        let headings = self.headings.iter().map(|(_, h)| h.clone()).collect();
+        self.sections.push(Section { start: self.text.len(), headings });
+        self.line(title);
+        self.text.push('\n');
+    }
+
+    pub(crate) fn line(&mut self, line: &str) {
+        self.text.push_str(line.trim_end());
+        self.text.push('\n');
+    }
+
+    // Starts a new page, e.g. for each slide of a presentation
+    pub(crate) fn page(&mut self) {
+        if self.text.len() > 0 {
+            self.text.push('\x0c');
+        }
+    }
+
+    pub(crate) fn into_document(self, metadata: BTreeMap<String, String>) -> Document {
+        let pages = self.text.split('\x0c').map(String::from).collect();
+        Document { pages, metadata, sections: self.sections }
+    }
+}
+
+// Scalar values, and lists of them joined by commas.  Nested tables are left out.
+fn yaml_value(value: &serde_yaml::Value) -> Option<String> {
+    match value {
+        serde_yaml::Value::String(s) => Some(s.clone()),
+        serde_yaml::Value::Number(n) => Some(n.to_string()),
+        serde_yaml::Value::Bool(b) => Some(b.to_string()),
+        serde_yaml::Value::Sequence(items) => {
+            let items = items.iter().filter_map(yaml_value).collect::<Vec<String>>();
+            if items.len() > 0 { Some(items.join(", ")) } else { None }
+        }
+        _ => None,
+    }
+}
+
+// Splits YAML (---) or TOML (+++) front matter off the start of a Markdown
+// document.  Only `key = value` lines of TOML front matter are read.
+fn front_matter(text: &str) -> (BTreeMap<String, String>, &str) {
+    let mut metadata = BTreeMap::new();
+    let fence = match text.lines().next() {
+        Some(line) if line.trim_end() == "---" => "---",
+        Some(line) if line.trim_end() == "+++" => "+++",
+        _ => return (metadata, text),
+    };
+    let body_start = text.find('\n').map(|i| i + 1).unwrap_or(text.len());
+    let mut offset = body_start;
+    let mut end = None;
+    for line in text[body_start..].split_inclusive('\n') {
+        let trimmed = line.trim_end();
+        if trimmed == fence || (fence == "---" && trimmed == "...") {
+            end = Some((offset, offset + line.len()));
+            break;
+        }
+        offset += line.len();
+    }
+    let (matter_end, rest_start) = match end {
+        Some(end) => end,
+        None => return (metadata, text),
+    };
+    let matter = &text[body_start..matter_end];
+    if fence == "---" {
+        match serde_yaml::from_str::<serde_yaml::Value>(matter) {
+            Ok(serde_yaml::Value::Mapping(map)) => {
+                for (key, value) in map.iter() {
+                    if let (Some(key), Some(value)) = (key.as_str(), yaml_value(value)) {
+                        metadata.insert(key.to_lowercase(), value);
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => log::debug!("Ignoring unreadable front matter: {}", e),
+        }
+    } else {
+        for line in matter.lines() {
+            if let Some((key, value)) = line.split_once('=') {
+                let value = value.trim().trim_matches('"').trim_matches('\'');
+                if value.len() > 0 {
+                    metadata.insert(key.trim().to_lowercase(), value.to_string());
+                }
+            }
+        }
+    }
+    (metadata, &text[rest_start..])
+}
+
+// Drops emphasis markers that open or close a word, leaving ones inside words
+// (snake_case) or standing alone (a * b)
+fn strip_emphasis(line: &str, markers: &[char]) -> String {
+    let chars = line.chars().collect::<Vec<char>>();
+    let boundary = |c: Option<char>| match c {
+        None => true,
+        Some(c) => markers.contains(&c) || !c.is_alphanumeric(),
+    };
+    let blank = |c: Option<char>| c.map(|c| c.is_whitespace()).unwrap_or(true);
+    let mut out = String::with_capacity(line.len());
+    for i in 0..chars.len() {
+        let c = chars[i];
+        if markers.contains(&c) {
+            let before = if i > 0 { Some(chars[i - 1]) } else { None };
+            let after = chars.get(i + 1).copied();
+            let opens = boundary(before) && !blank(after);
+            let closes = boundary(after) && !blank(before);
+            if opens || closes {
+                continue;
+            }
+        }
+        out.push(c);
+    }
+    out
+}
+
+// Strips a list bullet or number from the start of a line
+fn strip_bullet(line: &str) -> &str {
+    let trimmed = line.trim_start();
+    for bullet in ["- ", "+ ", "* "] {
+        if let Some(rest) = trimmed.strip_prefix(bullet) {
+            return rest;
+        }
+    }
+    let digits = trimmed.chars().take_while(|c| c.is_ascii_digit()).count();
+    if digits > 0 {
+        let rest = &trimmed[digits..];
+        if let Some(rest) = rest.strip_prefix(". ").or_else(|| rest.strip_prefix(") ")) {
+            return rest;
+        }
+    }
+    line
+}
+
+// Table rows lose their borders, separator rows are dropped
+fn table_row(line: &str) -> Option<String> {
+    let trimmed = line.trim();
+    if !trimmed.starts_with('|') {
+        return Some(line.to_string());
+    }
+    if trimmed.chars().all(|c| "|-+=: ".contains(c)) {
+        return None;
+    }
+    let cells = trimmed.trim_matches('|').split('|').map(|c| c.trim()).collect::<Vec<&str>>();
+    Some(cells.join("  "))
+}
+
+pub struct MarkdownParser;
+
+impl MarkdownParser {
+    fn outline(text: &str) -> Outline {
+        let mut outline = Outline::new();
+        let mut line = String::new();
+        // the level and text of the heading being read
+        let mut heading: Option<(usize, String)> = None;
+        let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS | Options::ENABLE_FOOTNOTES;
+        for event in pulldown_cmark::Parser::new_ext(text, options) {
+            match event {
+                Event::Start(Tag::Heading(level, _, _)) => {
+                    if line.trim().len() > 0 {
+                        outline.line(&line);
+                    }
+                    line.clear();
+                    heading = Some((level as usize, String::new()));
+                }
+                Event::End(Tag::Heading(..)) => {
+                    if let Some((level, title)) = heading.take() {
+                        outline.heading(level, &title);
+                    }
+                }
+                Event::Text(t) | Event::Code(t) => match heading.as_mut() {
+                    Some((_, title)) => title.push_str(&t),
+                    None => line.push_str(&t),
+                },
+                Event::SoftBreak | Event::HardBreak => match heading.as_mut() {
+                    Some((_, title)) => title.push(' '),
+                    None => {
+                        outline.line(&line);
+                        line.clear();
+                    }
+                },
+                Event::End(Tag::TableCell) => line.push_str("  "),
+                Event::End(Tag::Paragraph) | Event::End(Tag::CodeBlock(_)) | Event::End(Tag::BlockQuote) => {
+                    outline.line(&line);
+                    line.clear();
+                    outline.text.push('\n');
+                }
+                Event::End(Tag::Item) | Event::End(Tag::TableHead) | Event::End(Tag::TableRow) => {
+                    if line.trim().len() > 0 {
+                        outline.line(&line);
+                    }
+                    line.clear();
+                }
+                Event::Rule => outline.text.push('\n'),
+                // inline and block html, which is mostly layout
+                _ => {}
+            }
+        }
+        if line.trim().len() > 0 {
+            outline.line(&line);
+        }
+        outline
+    }
+}
+
+impl DocumentParser for MarkdownParser {
+    fn name(&self) -> &'static str {
+        "markdown"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["text/markdown", "text/x-markdown"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["md", "markdown", "mdown", "mkd"]
+    }
+
+    fn parse(&self, filename: &str) -> Result<Document, Box<dyn Error>> {
+        let text = fs::read_to_string(filename)?;
+        let (metadata, body) = front_matter(&text);
+        Ok(Self::outline(body).into_document(metadata))
+    }
+}
+
+pub struct RstParser;
+
+// Characters reStructuredText section titles can be underlined with
+const RST_ADORNMENTS: &str = "=-`:'\"~^_*+#<>.";
+
+// The character a line of one repeated adornment character is made of
+fn rst_adornment(line: &str) -> Option<char> {
+    let line = line.trim_end();
+    let first = line.chars().next()?;
+    if line.len() < 2 || !RST_ADORNMENTS.contains(first) || !line.chars().all(|c| c == first) {
+        return None;
+    }
+    Some(first)
+}
+
+// The text of a `name:: args` directive or `_target:` line after the `.. `
+// of an explicit markup block, or None for a comment
+fn rst_directive(rest: &str) -> Option<&str> {
+    let (name, _) = rest.split_once("::")?;
+    if name.len() > 0 && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '|' || c == ' ') {
+        Some(name.trim())
+    } else {
+        None
+    }
+}
+
+// Drops the backquotes, roles and link targets of inline markup
+fn rst_inline(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(i) = rest.find('`') {
+        out.push_str(&rest[..i]);
+        // a :role: right before the backquote
+        if out.ends_with(':') {
+            let head = &out[..out.len() - 1];
+            let j = head.rfind(|c: char| c.is_whitespace() || c == '(').map(|j| j + 1).unwrap_or(0);
+            let role = &head[j..];
+            if role.starts_with(':') && role[1..].chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == ':') {
+                out.truncate(j);
+            }
+        }
+        let literal = rest[i..].starts_with("``");
+        let quote = if literal { "``" } else { "`" };
+        let inner_start = i + quote.len();
+        let inner_end = match rest[inner_start..].find(quote) {
+            Some(j) => inner_start + j,
+            None => {
+                out.push_str(&rest[i..]);
+                return out;
+            }
+        };
+        let mut inner = &rest[inner_start..inner_end];
+        // `text <target>`_ links and :ref:`text <target>` roles
+        if !literal && inner.ends_with('>') {
+            if let Some(j) = inner.rfind(" <") {
+                inner = &inner[..j];
+            }
+        }
+        out.push_str(inner);
+        rest = &rest[inner_end + quote.len()..];
+        rest = rest.strip_prefix("__").or_else(|| rest.strip_prefix('_')).unwrap_or(rest);
+    }
+    out.push_str(rest);
+    strip_emphasis(&out, &['*'])
+}
+
+impl RstParser {
+    fn parse_text(text: &str) -> Document {
+        let mut outline = Outline::new();
+        let mut metadata = BTreeMap::new();
+        let lines = text.lines().collect::<Vec<&str>>();
+        // adornment styles, the character and whether it has an overline, in
+        // the order they appear, which gives their level
+        let mut styles: Vec<(char, bool)> = Vec::new();
+        let mut seen_body = false;
+        // inside a comment, target or image directive, whose indented lines
+        // are skipped
+        let mut skipping = false;
+        let mut i = 0;
+        while i < lines.len() {
+            let line = lines[i];
+            let indented = line.starts_with(' ') || line.starts_with('\t');
+            if skipping {
+                if line.trim().len() == 0 || indented {
+                    i += 1;
+                    continue;
+                }
+                skipping = false;
+            }
+
+            // section titles: text with an underline, or an overline too
+            let title = match (rst_adornment(line), lines.get(i + 1), lines.get(i + 2)) {
+                (Some(over), Some(text), Some(under)) if rst_adornment(under) == Some(over) && text.trim().len() > 0 && rst_adornment(text).is_none() => {
+                    Some((over, true, text.trim(), 3))
+                }
+                (None, Some(under), _) if !indented && line.trim().len() > 0 && rst_adornment(under).is_some()
+                    && under.trim_end().chars().count() >= line.trim().chars().count() => {
+                    Some((rst_adornment(under).unwrap(), false, line.trim(), 2))
+                }
+                _ => None,
+            };
+            if let Some((c, overline, title, consumed)) = title {
+                let style = (c, overline);
+                let level = match styles.iter().position(|s| *s == style) {
+                    Some(p) => p + 1,
+                    None => {
+                        styles.push(style);
+                        styles.len()
+                    }
+                };
+                let title = rst_inline(title);
+                // a title before any text is the document's
+                if !seen_body && outline.sections.len() == 0 {
+                    metadata.insert(String::from("title"), title.clone());
+                }
+                outline.heading(level, &title);
+                i += consumed;
+                continue;
+            }
+            if rst_adornment(line).is_some() {
+                // a transition
+                i += 1;
+                continue;
+            }
+
+            let trimmed = line.trim();
+            if let Some(rest) = trimmed.strip_prefix("..") {
+                if rest.len() == 0 || rest.starts_with(' ') {
+                    match rst_directive(rest.trim()) {
+                        // admonitions and code blocks keep their content
+                        Some(name) if !["image", "figure", "include", "raw", "toctree", "contents"].contains(&name) && !name.starts_with('|') => {}
+                        _ => skipping = true,
+                    }
+                    if rest.trim().starts_with('_') || rest.trim().starts_with("[") {
+                        skipping = true;
+                    }
+                    i += 1;
+                    continue;
+                }
+            }
+            // directive options
+            if indented && trimmed.starts_with(':') && trimmed[1..].contains(": ") && i > 0 && lines[i - 1].trim().starts_with("..") {
+                i += 1;
+                continue;
+            }
+            // bibliographic fields before the body, e.g. :Author: Jane
+            if !seen_body && !indented && trimmed.starts_with(':') {
+                if let Some((key, value)) = trimmed[1..].split_once(':') {
+                    if value.trim().len() > 0 {
+                        metadata.insert(key.trim().to_lowercase(), rst_inline(value.trim()));
+                        i += 1;
+                        continue;
+                    }
+                }
+            }
+            if trimmed.len() > 0 {
+                seen_body = true;
+            }
+            // literal blocks are introduced by a trailing ::
+            let line = match line.trim_end().strip_suffix("::") {
+                Some(head) if head.trim().len() == 0 => "",
+                Some(head) if head.ends_with(' ') => head.trim_end(),
+                Some(_) => &line.trim_end()[..line.trim_end().len() - 1],
+                None => line,
+            };
+            match table_row(strip_bullet(line)) {
+                Some(row) => outline.line(&rst_inline(&row)),
+                None => {}
+            }
+            i += 1;
+        }
+        outline.into_document(metadata)
+    }
+}
+
+impl DocumentParser for RstParser {
+    fn name(&self) -> &'static str {
+        "rst"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["text/x-rst", "text/prs.fallenstein.rst"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["rst", "rest"]
+    }
+
+    fn parse(&self, filename: &str) -> Result<Document, Box<dyn Error>> {
+        let text = fs::read_to_string(filename)?;
+        Ok(Self::parse_text(&text))
+    }
+}
+
+pub struct OrgParser;
+
+// Org keywords that describe the document
+const ORG_KEYWORDS: [&str; 8] = ["title", "subtitle", "author", "email", "date", "description", "keywords", "filetags"];
+
+// Replaces [[target][description]] links by the description, and
+// [[target]] by the target
+fn org_links(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(i) = rest.find("[[") {
+        let end = match rest[i..].find("]]") {
+            Some(j) => i + j,
+            None => break,
+        };
+        out.push_str(&rest[..i]);
+        let link = &rest[i + 2..end];
+        match link.split_once("][") {
+            Some((_, description)) => out.push_str(description),
+            None => out.push_str(link),
+        }
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// The title of a headline without its todo keyword, priority and tags
+fn org_headline(title: &str) -> String {
+    let mut title = title.trim();
+    for keyword in ["TODO ", "DONE ", "NEXT ", "WAITING ", "CANCELLED "] {
+        if let Some(rest) = title.strip_prefix(keyword) {
+            title = rest.trim_start();
+        }
+    }
+    if title.starts_with("[#") && title.as_bytes().get(3) == Some(&b']') {
+        title = title[4..].trim_start();
+    }
+    if title.ends_with(':') {
+        if let Some(i) = title.trim_end_matches(':').rfind(char::is_whitespace) {
+            let tags = &title[i + 1..];
+            if tags.starts_with(':') && !tags.contains(' ') {
+                title = title[..i].trim_end();
+            }
+        }
+    }
+    strip_emphasis(&org_links(title), &['*', '=', '~'])
+}
+
+impl OrgParser {
+    fn parse_text(text: &str) -> Document {
+        let mut outline = Outline::new();
+        let mut metadata = BTreeMap::new();
+        let mut in_drawer = false;
+        for line in text.lines() {
+            let trimmed = line.trim();
+            if in_drawer {
+                if trimmed.eq_ignore_ascii_case(":END:") {
+                    in_drawer = false;
+                }
+                continue;
+            }
+            let stars = line.chars().take_while(|c| *c == '*').count();
+            if stars > 0 && line[stars..].starts_with(' ') {
+                outline.heading(stars, &org_headline(&line[stars..]));
+                continue;
+            }
+            // property and logbook drawers
+            if trimmed.len() > 2 && trimmed.starts_with(':') && trimmed.ends_with(':') && !trimmed[1..trimmed.len() - 1].contains(|c: char| c.is_whitespace() || c == ':') {
+                in_drawer = true;
+                continue;
+            }
+            if let Some(rest) = trimmed.strip_prefix("#+") {
+                if let Some((key, value)) = rest.split_once(':') {
+                    let key = key.trim().to_lowercase();
+                    if ORG_KEYWORDS.contains(&key.as_str()) && value.trim().len() > 0 {
+                        metadata.insert(key, org_links(value.trim()));
+                    }
+                }
+                // block delimiters and settings; block content is kept
+                continue;
+            }
+            if trimmed == "#" || trimmed.starts_with("# ") {
+                continue;
+            }
+            if ["SCHEDULED:", "DEADLINE:", "CLOSED:"].iter().any(|k| trimmed.starts_with(k)) {
+                continue;
+            }
+            if let Some(row) = table_row(strip_bullet(line)) {
+                outline.line(&strip_emphasis(&org_links(&row), &['*', '=', '~']));
+            }
+        }
+        outline.into_document(metadata)
+    }
+}
+
+impl DocumentParser for OrgParser {
+    fn name(&self) -> &'static str {
+        "org"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["text/org", "text/x-org"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["org"]
+    }
+
+    fn parse(&self, filename: &str) -> Result<Document, Box<dyn Error>> {
+        let text = fs::read_to_string(filename)?;
+        Ok(Self::parse_text(&text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The breadcrumbs of each section and the line it starts with
+    fn sections(document: &Document) -> Vec<(String, String)> {
+        let text = document.text();
+        document.sections.iter()
+            .map(|s| (s.headings.join(" > "), text[s.start..].lines().next().unwrap_or("").to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn markdown_front_matter() {
+        let (metadata, body) = front_matter("---\ntitle: Notes\ntags: [rust, search]\ndraft: true\n---\n# Intro\n");
+        assert_eq!(metadata.get("title").map(String::as_str), Some("Notes"));
+        assert_eq!(metadata.get("tags").map(String::as_str), Some("rust, search"));
+        assert_eq!(metadata.get("draft").map(String::as_str), Some("true"));
+        assert_eq!(body, "# Intro\n");
+
+        let (metadata, body) = front_matter("+++\ntitle = \"Notes\"\n+++\ntext\n");
+        assert_eq!(metadata.get("title").map(String::as_str), Some("Notes"));
+        assert_eq!(body, "text\n");
+
+        let (metadata, body) = front_matter("---\nunterminated\n");
+        assert!(metadata.is_empty());
+        assert_eq!(body, "---\nunterminated\n");
+    }
+
+    #[test]
+    fn markdown_text_and_sections() {
+        let document = MarkdownParser::outline("# Guide\n\nSome *emphasised* `code`.\n\n## Install\n\n- one\n- two\n\n# Usage\n\n| a | b |\n|---|---|\n| 1 | 2 |\n").into_document(BTreeMap::new());
+        let text = document.text();
+        assert!(text.contains("Some emphasised code."));
+        assert!(text.contains("one\ntwo\n"));
+        assert!(text.contains("1  2"));
+        assert!(!text.contains('*') && !text.contains('`') && !text.contains('|'));
+        assert_eq!(sections(&document), vec![
+            (String::from("Guide"), String::from("Guide")),
+            (String::from("Guide > Install"), String::from("Install")),
+            (String::from("Usage"), String::from("Usage")),
+        ]);
+    }
+
+    #[test]
+    fn rst_adornment_levels() {
+        let document = RstParser::parse_text("=====\nTitle\n=====\n\n:Author: Jane\n\nIntro with **bold** and ``code``.\n\nFirst\n-----\n\nSee `the docs <https://example.com>`_.\n\nDeeper\n~~~~~~\n\ntext\n\nSecond\n------\n\n.. image:: pic.png\n   :alt: a picture\n\nend\n");
+        assert_eq!(document.metadata.get("title").map(String::as_str), Some("Title"));
+        assert_eq!(document.metadata.get("author").map(String::as_str), Some("Jane"));
+        assert_eq!(sections(&document), vec![
+            (String::from("Title"), String::from("Title")),
+            (String::from("Title > First"), String::from("First")),
+            (String::from("Title > First > Deeper"), String::from("Deeper")),
+            (String::from("Title > Second"), String::from("Second")),
+        ]);
+        let text = document.text();
+        assert!(text.contains("Intro with bold and code."));
+        assert!(text.contains("See the docs."));
+        assert!(!text.contains("pic.png") && !text.contains("a picture"));
+    }
+
+    #[test]
+    fn rst_inline_markup() {
+        assert_eq!(rst_inline("use :func:`parse` here"), "use parse here");
+        assert_eq!(rst_inline("a `link`__ and *stress*"), "a link and stress");
+        assert_eq!(rst_inline("unclosed `quote"), "unclosed `quote");
+    }
+
+    #[test]
+    fn org_headlines_and_keywords() {
+        let document = OrgParser::parse_text("#+TITLE: Plans\n#+STARTUP: overview\n* TODO [#A] Ship *it* :work:\n  SCHEDULED: <2023-05-01>\n  :PROPERTIES:\n  :ID: 42\n  :END:\n  See [[https://example.com][the site]].\n** Sub ~task~\n# a comment\nbody\n");
+        assert_eq!(document.metadata.get("title").map(String::as_str), Some("Plans"));
+        assert!(!document.metadata.contains_key("startup"));
+        assert_eq!(sections(&document), vec![
+            (String::from("Ship it"), String::from("Ship it")),
+            (String::from("Ship it > Sub task"), String::from("Sub task")),
+        ]);
+        let text = document.text();
+        assert!(text.contains("See the site."));
+        assert!(!text.contains("SCHEDULED") && !text.contains(":ID:") && !text.contains("a comment"));
+    }
+
+    #[test]
+    fn org_headline_priority() {
+        assert_eq!(org_headline("[#B] Call"), "Call");
+        assert_eq!(org_headline("[#é] Call"), "[#é] Call");
+        assert_eq!(org_headline("[#"), "[#");
+    }
+
+    #[test]
+    fn emphasis_inside_words_is_kept() {
+        assert_eq!(strip_emphasis("*bold* snake_case a * b _it_", &['*', '_']), "bold snake_case a * b it");
+    }
+}
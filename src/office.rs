@@ -0,0 +1,798 @@
+// Copyright: (c) 2023 Sureshkumar T
+// License: Apache-2.0
+
+// Parsers for office documents: Word, Excel and PowerPoint files (OOXML), and
+// their OpenDocument counterparts.  Both are zip archives of XML parts, read
+// here without any office suite installed.
+//
+// Paragraphs and table rows become lines of text, with the cells of a row
+// separated by two spaces.  Headings of text documents become sections.  Each
+// sheet or slide starts a new page, so results carry its number, under a
+// heading with the sheet's name or the slide's number.  Speaker notes follow
+// their slide under a Notes heading.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+use crate::error;
+use crate::markup::Outline;
+use crate::parsers::{Document, DocumentParser};
+use crate::settings;
+
+type Archive = ZipArchive<File>;
+
+fn open(filename: &str) -> Result<Archive, Box<dyn Error>> {
+    Ok(ZipArchive::new(File::open(filename)?)?)
+}
+
+// A part of the archive, or None if it has no such part.  Parts compress
+// well, so a small file can hold parts far too large to read into memory;
+// those fail the parse.
+fn read_part(archive: &mut Archive, name: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let part = match archive.by_name(name) {
+        Ok(part) => part,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(Box::new(e)),
+    };
+    let limit = settings::get_config(None).max_part_mb * 1024 * 1024;
+    if part.size() > limit {
+        return Err(Box::new(error::Error::new(&format!("{} uncompresses to {} bytes, more than parser.max_part_mb", name, part.size()))));
+    }
+    // the size in the archive's directory may be wrong, so read no more than
+    // the limit either way
+    let mut xml = String::new();
+    part.take(limit + 1).read_to_string(&mut xml)?;
+    if xml.len() as u64 > limit {
+        return Err(Box::new(error::Error::new(&format!("{} uncompresses to more than parser.max_part_mb", name))));
+    }
+    Ok(Some(xml))
+}
+
+fn require_part(archive: &mut Archive, name: &str) -> Result<String, Box<dyn Error>> {
+    read_part(archive, name)?
+        .ok_or_else(|| Box::new(error::Error::new(&format!("no {} in the archive", name))) as Box<dyn Error>)
+}
+
+// What the walk of an XML part sees.  Elements are known by their local name,
+// without the namespace prefix.  Empty elements start and end.
+enum Xml<'a> {
+    Start(&'a BytesStart<'a>),
+    End(&'a [u8]),
+    Text(&'a str),
+}
+
+fn walk<F: FnMut(Xml)>(xml: &str, mut handle: F) -> Result<(), Box<dyn Error>> {
+    let mut reader = Reader::from_str(xml);
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => handle(Xml::Start(&e)),
+            Event::Empty(e) => {
+                handle(Xml::Start(&e));
+                handle(Xml::End(e.local_name().as_ref()));
+            }
+            Event::End(e) => handle(Xml::End(e.local_name().as_ref())),
+            Event::Text(t) => {
+                let text = t.unescape().map(|t| t.into_owned())
+                    .unwrap_or_else(|_| String::from_utf8_lossy(&t).into_owned());
+                handle(Xml::Text(&text));
+            }
+            Event::CData(t) => handle(Xml::Text(&String::from_utf8_lossy(&t))),
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// The value of an attribute by its qualified name, e.g. r:id
+fn attr(element: &BytesStart, name: &str) -> Option<String> {
+    element.attributes().flatten()
+        .find(|a| a.key.as_ref() == name.as_bytes())
+        .and_then(|a| a.unescape_value().ok().map(|v| v.into_owned()))
+}
+
+// Collects the text of paragraphs and table rows into an outline
+struct Writer {
+    outline: Outline,
+    line: String,
+    // a heading written before the next line, for parts that may be empty
+    pending: Option<(usize, String)>,
+}
+
+impl Writer {
+    fn new() -> Self {
+        Writer { outline: Outline::new(), line: String::new(), pending: None }
+    }
+
+    fn text(&mut self, text: &str) {
+        self.line.push_str(text);
+    }
+
+    // Ends a table cell
+    fn cell(&mut self) {
+        let len = self.line.trim_end().len();
+        self.line.truncate(len);
+        if len > 0 {
+            self.line.push_str("  ");
+        }
+    }
+
+    fn flush(&mut self) {
+        let line = std::mem::take(&mut self.line);
+        if line.trim().len() > 0 {
+            if let Some((level, title)) = self.pending.take() {
+                self.outline.heading(level, &title);
+            }
+            self.outline.line(line.trim());
+        }
+    }
+
+    // Makes the line read so far a heading
+    fn heading(&mut self, level: usize) {
+        let title = std::mem::take(&mut self.line);
+        if title.trim().len() > 0 {
+            if let Some((outer, pending)) = self.pending.take() {
+                self.outline.heading(outer, &pending);
+            }
+            self.outline.heading(level, &title);
+        }
+    }
+
+    // Starts the page of a sheet or slide
+    fn page(&mut self, title: &str) {
+        self.flush();
+        self.pending = None;
+        self.outline.page();
+        self.outline.heading(1, title);
+    }
+}
+
+// The title, author and subject from the document properties, which are in
+// docProps/core.xml of OOXML files and meta.xml of OpenDocument ones
+fn properties(xml: &str) -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    let mut metadata = BTreeMap::new();
+    let mut field: Option<&str> = None;
+    let mut value = String::new();
+    walk(xml, |x| match x {
+        Xml::Start(e) => {
+            field = match e.name().as_ref() {
+                b"dc:title" => Some("title"),
+                b"dc:subject" => Some("subject"),
+                b"meta:initial-creator" => Some("author"),
+                // the last editor of OpenDocument files
+                b"dc:creator" => Some("creator"),
+                _ => None,
+            };
+            value.clear();
+        }
+        Xml::Text(text) => value.push_str(text),
+        Xml::End(_) => {
+            let text = value.trim();
+            match field.take() {
+                Some(_) if text.len() == 0 => {}
+                Some("creator") => {
+                    metadata.entry(String::from("author")).or_insert(text.to_string());
+                }
+                Some(field) => {
+                    metadata.insert(field.to_string(), text.to_string());
+                }
+                None => {}
+            }
+        }
+    })?;
+    Ok(metadata)
+}
+
+fn ooxml_properties(archive: &mut Archive) -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    match read_part(archive, "docProps/core.xml")? {
+        Some(xml) => properties(&xml),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+// The path in the archive of a relationship's target, which is relative to
+// the directory of the part it is from
+fn resolve(part: &str, target: &str) -> String {
+    if let Some(absolute) = target.strip_prefix('/') {
+        return absolute.to_string();
+    }
+    let mut path = part.split('/').collect::<Vec<&str>>();
+    path.pop();
+    for segment in target.split('/') {
+        match segment {
+            ".." => {
+                path.pop();
+            }
+            "." | "" => {}
+            segment => path.push(segment),
+        }
+    }
+    path.join("/")
+}
+
+// The relationships of a part by id, as their type and the path of their target
+fn relationships(archive: &mut Archive, part: &str) -> Result<HashMap<String, (String, String)>, Box<dyn Error>> {
+    let (dir, name) = part.rsplit_once('/').unwrap_or(("", part));
+    let rels = if dir.len() > 0 { format!("{}/_rels/{}.rels", dir, name) } else { format!("_rels/{}.rels", name) };
+    let mut targets = HashMap::new();
+    if let Some(xml) = read_part(archive, &rels)? {
+        walk(&xml, |x| {
+            if let Xml::Start(e) = x {
+                if e.local_name().as_ref() == b"Relationship" {
+                    if let (Some(id), Some(target)) = (attr(e, "Id"), attr(e, "Target")) {
+                        let kind = attr(e, "Type").unwrap_or_default();
+                        targets.insert(id, (kind, resolve(part, &target)));
+                    }
+                }
+            }
+        })?;
+    }
+    Ok(targets)
+}
+
+pub struct DocxParser;
+
+impl DocxParser {
+    // Heading levels of paragraph styles by style id, from their names
+    // ("heading 1", "Title") or outline levels, as style ids are localized
+    fn heading_styles(xml: &str) -> Result<HashMap<String, usize>, Box<dyn Error>> {
+        let mut levels = HashMap::new();
+        let mut style: Option<String> = None;
+        walk(xml, |x| match x {
+            Xml::Start(e) => match e.local_name().as_ref() {
+                b"style" => style = attr(e, "w:styleId"),
+                b"name" => {
+                    let name = attr(e, "w:val").unwrap_or_default().to_lowercase();
+                    let level = match name.as_str() {
+                        "title" => Some(1),
+                        name => name.strip_prefix("heading ").and_then(|l| l.parse::<usize>().ok()),
+                    };
+                    if let (Some(id), Some(level)) = (&style, level) {
+                        levels.insert(id.clone(), level);
+                    }
+                }
+                b"outlineLvl" => {
+                    // level 9 is body text
+                    let level = attr(e, "w:val").and_then(|l| l.parse::<usize>().ok()).filter(|l| *l < 9);
+                    if let (Some(id), Some(level)) = (&style, level) {
+                        levels.entry(id.clone()).or_insert(level + 1);
+                    }
+                }
+                _ => {}
+            },
+            Xml::End(b"style") => style = None,
+            _ => {}
+        })?;
+        Ok(levels)
+    }
+
+    fn body(xml: &str, styles: &HashMap<String, usize>, writer: &mut Writer) -> Result<(), Box<dyn Error>> {
+        // heading level of the paragraph being read
+        let mut level: Option<usize> = None;
+        let mut in_text = false;
+        let mut cells = 0;
+        // alternate renderings of drawings repeat their text
+        let mut fallback = 0;
+        walk(xml, |x| match x {
+            Xml::Start(e) => match e.local_name().as_ref() {
+                b"p" => level = None,
+                b"pStyle" => level = attr(e, "w:val").and_then(|s| styles.get(&s).copied()),
+                b"outlineLvl" => {
+                    let outline = attr(e, "w:val").and_then(|l| l.parse::<usize>().ok()).filter(|l| *l < 9);
+                    level = level.or(outline.map(|l| l + 1));
+                }
+                b"t" => in_text = true,
+                b"tab" => writer.text(" "),
+                b"br" | b"cr" if cells > 0 || level.is_some() => writer.text(" "),
+                b"br" | b"cr" => writer.flush(),
+                b"tc" => cells += 1,
+                b"Fallback" => fallback += 1,
+                _ => {}
+            },
+            Xml::End(name) => match name {
+                b"t" => in_text = false,
+                b"p" if cells > 0 => writer.text(" "),
+                b"p" => match level.take() {
+                    Some(level) => writer.heading(level),
+                    None => writer.flush(),
+                },
+                b"tc" => {
+                    cells -= 1;
+                    writer.cell();
+                }
+                b"tr" => writer.flush(),
+                b"Fallback" => fallback -= 1,
+                _ => {}
+            },
+            Xml::Text(text) => {
+                if in_text && fallback == 0 {
+                    writer.text(text);
+                }
+            }
+        })?;
+        writer.flush();
+        Ok(())
+    }
+}
+
+impl DocumentParser for DocxParser {
+    fn name(&self) -> &'static str {
+        "docx"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/vnd.openxmlformats-officedocument.wordprocessingml.document"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["docx", "docm"]
+    }
+
+    fn parse(&self, filename: &str) -> Result<Document, Box<dyn Error>> {
+        let mut archive = open(filename)?;
+        let styles = match read_part(&mut archive, "word/styles.xml")? {
+            Some(xml) => Self::heading_styles(&xml)?,
+            None => HashMap::new(),
+        };
+        let body = require_part(&mut archive, "word/document.xml")?;
+        let mut writer = Writer::new();
+        Self::body(&body, &styles, &mut writer)?;
+        Ok(writer.outline.into_document(ooxml_properties(&mut archive)?))
+    }
+}
+
+pub struct XlsxParser;
+
+impl XlsxParser {
+    fn shared_strings(xml: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut strings = Vec::new();
+        let mut current = String::new();
+        let mut in_text = false;
+        // phonetic guides of east asian text
+        let mut phonetic = 0;
+        walk(xml, |x| match x {
+            Xml::Start(e) => match e.local_name().as_ref() {
+                b"si" => current.clear(),
+                b"t" => in_text = true,
+                b"rPh" => phonetic += 1,
+                _ => {}
+            },
+            Xml::End(name) => match name {
+                b"si" => strings.push(std::mem::take(&mut current)),
+                b"t" => in_text = false,
+                b"rPh" => phonetic -= 1,
+                _ => {}
+            },
+            Xml::Text(text) => {
+                if in_text && phonetic == 0 {
+                    current.push_str(text);
+                }
+            }
+        })?;
+        Ok(strings)
+    }
+
+    // Cell values row by row.  Formulas are left out for their results.
+    fn sheet(xml: &str, shared: &[String], writer: &mut Writer) -> Result<(), Box<dyn Error>> {
+        let mut kind: Option<String> = None;
+        let mut value = String::new();
+        let mut in_value = false;
+        walk(xml, |x| match x {
+            Xml::Start(e) => match e.local_name().as_ref() {
+                b"c" => {
+                    kind = attr(e, "t");
+                    value.clear();
+                }
+                b"v" | b"t" => in_value = true,
+                _ => {}
+            },
+            Xml::End(name) => match name {
+                b"v" | b"t" => in_value = false,
+                b"c" => {
+                    let text = match kind.as_deref() {
+                        Some("s") => value.trim().parse::<usize>().ok()
+                            .and_then(|i| shared.get(i)).cloned().unwrap_or_default(),
+                        Some("b") => String::from(if value.trim() == "1" { "TRUE" } else { "FALSE" }),
+                        _ => value.clone(),
+                    };
+                    writer.text(&text);
+                    writer.cell();
+                }
+                b"row" => writer.flush(),
+                _ => {}
+            },
+            Xml::Text(text) => {
+                if in_value {
+                    value.push_str(text);
+                }
+            }
+        })?;
+        writer.flush();
+        Ok(())
+    }
+}
+
+impl DocumentParser for XlsxParser {
+    fn name(&self) -> &'static str {
+        "xlsx"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["xlsx", "xlsm"]
+    }
+
+    fn parse(&self, filename: &str) -> Result<Document, Box<dyn Error>> {
+        let mut archive = open(filename)?;
+        let shared = match read_part(&mut archive, "xl/sharedStrings.xml")? {
+            Some(xml) => Self::shared_strings(&xml)?,
+            None => Vec::new(),
+        };
+        let workbook = require_part(&mut archive, "xl/workbook.xml")?;
+        let mut sheets = Vec::new();
+        walk(&workbook, |x| {
+            if let Xml::Start(e) = x {
+                if e.local_name().as_ref() == b"sheet" {
+                    sheets.push((attr(e, "name"), attr(e, "r:id")));
+                }
+            }
+        })?;
+        let targets = relationships(&mut archive, "xl/workbook.xml")?;
+        let mut writer = Writer::new();
+        for (i, (name, id)) in sheets.iter().enumerate() {
+            let title = name.clone().unwrap_or(format!("Sheet {}", i + 1));
+            writer.page(&title);
+            let part = id.as_ref().and_then(|id| targets.get(id)).map(|(_, path)| path.clone());
+            if let Some(xml) = match part { Some(part) => read_part(&mut archive, &part)?, None => None } {
+                Self::sheet(&xml, &shared, &mut writer)?;
+            }
+        }
+        let mut metadata = ooxml_properties(&mut archive)?;
+        metadata.insert(String::from("sheets"), sheets.len().to_string());
+        Ok(writer.outline.into_document(metadata))
+    }
+}
+
+pub struct PptxParser;
+
+// Placeholders repeated on every slide or notes page
+const PPTX_SKIPPED_PLACEHOLDERS: [&str; 5] = ["sldNum", "dt", "ftr", "hdr", "sldImg"];
+
+impl PptxParser {
+    // The text of a slide or its notes page
+    fn text(xml: &str, writer: &mut Writer) -> Result<(), Box<dyn Error>> {
+        let mut in_text = false;
+        // in a shape of a skipped placeholder
+        let mut skip = false;
+        let mut cells = 0;
+        let mut fallback = 0;
+        walk(xml, |x| match x {
+            Xml::Start(e) => match e.local_name().as_ref() {
+                b"sp" => skip = false,
+                b"ph" => skip = attr(e, "type").map(|t| PPTX_SKIPPED_PLACEHOLDERS.contains(&t.as_str())).unwrap_or(false),
+                b"t" => in_text = true,
+                b"br" if cells > 0 => writer.text(" "),
+                b"br" => writer.flush(),
+                b"tc" => cells += 1,
+                b"Fallback" => fallback += 1,
+                _ => {}
+            },
+            Xml::End(name) => match name {
+                b"t" => in_text = false,
+                b"sp" => skip = false,
+                b"p" if cells > 0 => writer.text(" "),
+                b"p" => writer.flush(),
+                b"tc" => {
+                    cells -= 1;
+                    writer.cell();
+                }
+                b"tr" => writer.flush(),
+                b"Fallback" => fallback -= 1,
+                _ => {}
+            },
+            Xml::Text(text) => {
+                if in_text && !skip && fallback == 0 {
+                    writer.text(text);
+                }
+            }
+        })?;
+        writer.flush();
+        Ok(())
+    }
+}
+
+impl DocumentParser for PptxParser {
+    fn name(&self) -> &'static str {
+        "pptx"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/vnd.openxmlformats-officedocument.presentationml.presentation"]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["pptx", "pptm"]
+    }
+
+    fn parse(&self, filename: &str) -> Result<Document, Box<dyn Error>> {
+        let mut archive = open(filename)?;
+        let presentation = require_part(&mut archive, "ppt/presentation.xml")?;
+        let mut ids = Vec::new();
+        walk(&presentation, |x| {
+            if let Xml::Start(e) = x {
+                if e.local_name().as_ref() == b"sldId" {
+                    ids.extend(attr(e, "r:id"));
+                }
+            }
+        })?;
+        let targets = relationships(&mut archive, "ppt/presentation.xml")?;
+        let mut writer = Writer::new();
+        for (i, id) in ids.iter().enumerate() {
+            writer.page(&format!("Slide {}", i + 1));
+            let slide = match targets.get(id) {
+                Some((_, slide)) => slide.clone(),
+                None => continue,
+            };
+            if let Some(xml) = read_part(&mut archive, &slide)? {
+                Self::text(&xml, &mut writer)?;
+            }
+            let notes = relationships(&mut archive, &slide)?.into_values()
+                .find(|(kind, _)| kind.ends_with("/notesSlide"))
+                .map(|(_, notes)| notes);
+            if let Some(xml) = match notes { Some(notes) => read_part(&mut archive, &notes)?, None => None } {
+                writer.pending = Some((2, String::from("Notes")));
+                Self::text(&xml, &mut writer)?;
+                writer.pending = None;
+            }
+        }
+        let mut metadata = ooxml_properties(&mut archive)?;
+        metadata.insert(String::from("slides"), ids.len().to_string());
+        Ok(writer.outline.into_document(metadata))
+    }
+}
+
+// Text documents, spreadsheets and presentations in OpenDocument format
+pub struct OdfParser;
+
+impl OdfParser {
+    fn content(xml: &str, writer: &mut Writer, metadata: &mut BTreeMap<String, String>) -> Result<(), Box<dyn Error>> {
+        let (mut spreadsheet, mut presentation) = (false, false);
+        let (mut sheets, mut slides) = (0, 0);
+        // depth of paragraphs and headings, whose text is read
+        let mut paragraphs = 0;
+        let mut level: Option<usize> = None;
+        let mut cells = 0;
+        // in comments or the deleted text of tracked changes
+        let mut skip = 0;
+        walk(xml, |x| match x {
+            Xml::Start(e) => match e.local_name().as_ref() {
+                b"spreadsheet" => spreadsheet = true,
+                b"presentation" => presentation = true,
+                b"table" if spreadsheet && cells == 0 => {
+                    sheets += 1;
+                    writer.page(&attr(e, "table:name").unwrap_or(format!("Sheet {}", sheets)));
+                }
+                b"page" if presentation => {
+                    slides += 1;
+                    writer.page(&format!("Slide {}", slides));
+                }
+                b"notes" if presentation => {
+                    writer.flush();
+                    writer.pending = Some((2, String::from("Notes")));
+                }
+                b"p" => paragraphs += 1,
+                b"h" => {
+                    writer.flush();
+                    paragraphs += 1;
+                    level = Some(attr(e, "text:outline-level").and_then(|l| l.parse::<usize>().ok()).unwrap_or(1));
+                }
+                b"s" => {
+                    let count = attr(e, "text:c").and_then(|c| c.parse::<usize>().ok()).unwrap_or(1);
+                    writer.text(&" ".repeat(count.min(80)));
+                }
+                b"tab" => writer.text(" "),
+                b"line-break" if cells > 0 || level.is_some() => writer.text(" "),
+                b"line-break" => writer.flush(),
+                b"table-cell" => cells += 1,
+                b"annotation" | b"tracked-changes" => skip += 1,
+                _ => {}
+            },
+            Xml::End(name) => match name {
+                b"p" => {
+                    paragraphs -= 1;
+                    if cells > 0 {
+                        writer.text(" ");
+                    } else if paragraphs == 0 {
+                        writer.flush();
+                    }
+                }
+                b"h" => {
+                    paragraphs -= 1;
+                    writer.heading(level.take().unwrap_or(1));
+                }
+                b"table-cell" => {
+                    cells -= 1;
+                    writer.cell();
+                }
+                b"table-row" => writer.flush(),
+                b"notes" if presentation => {
+                    writer.flush();
+                    writer.pending = None;
+                }
+                b"annotation" | b"tracked-changes" => skip -= 1,
+                _ => {}
+            },
+            Xml::Text(text) => {
+                if paragraphs > 0 && skip == 0 {
+                    writer.text(text);
+                }
+            }
+        })?;
+        writer.flush();
+        if spreadsheet {
+            metadata.insert(String::from("sheets"), sheets.to_string());
+        }
+        if presentation {
+            metadata.insert(String::from("slides"), slides.to_string());
+        }
+        Ok(())
+    }
+}
+
+impl DocumentParser for OdfParser {
+    fn name(&self) -> &'static str {
+        "opendocument"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &[
+            "application/vnd.oasis.opendocument.text",
+            "application/vnd.oasis.opendocument.spreadsheet",
+            "application/vnd.oasis.opendocument.presentation",
+        ]
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["odt", "ods", "odp"]
+    }
+
+    fn parse(&self, filename: &str) -> Result<Document, Box<dyn Error>> {
+        let mut archive = open(filename)?;
+        let mut metadata = match read_part(&mut archive, "meta.xml")? {
+            Some(xml) => properties(&xml)?,
+            None => BTreeMap::new(),
+        };
+        let content = require_part(&mut archive, "content.xml")?;
+        let mut writer = Writer::new();
+        Self::content(&content, &mut writer, &mut metadata)?;
+        Ok(writer.outline.into_document(metadata))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const W: &str = r#"xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main""#;
+
+    fn headings(document: &Document) -> Vec<String> {
+        document.sections.iter().map(|s| s.headings.join(" > ")).collect()
+    }
+
+    #[test]
+    fn docx_heading_styles() {
+        let styles = format!(r#"<w:styles {}>
+            <w:style w:type="paragraph" w:styleId="Titel"><w:name w:val="Title"/></w:style>
+            <w:style w:type="paragraph" w:styleId="berschrift1"><w:name w:val="heading 1"/></w:style>
+            <w:style w:type="paragraph" w:styleId="Custom"><w:name w:val="Chapter"/><w:pPr><w:outlineLvl w:val="1"/></w:pPr></w:style>
+            <w:style w:type="paragraph" w:styleId="Normal"><w:name w:val="Normal"/><w:pPr><w:outlineLvl w:val="9"/></w:pPr></w:style>
+            </w:styles>"#, W);
+        let levels = DocxParser::heading_styles(&styles).unwrap();
+        assert_eq!(levels.get("Titel"), Some(&1));
+        assert_eq!(levels.get("berschrift1"), Some(&1));
+        assert_eq!(levels.get("Custom"), Some(&2));
+        assert_eq!(levels.get("Normal"), None);
+    }
+
+    #[test]
+    fn docx_body() {
+        let styles = HashMap::from([(String::from("Heading1"), 1), (String::from("Heading2"), 2)]);
+        let body = format!(r#"<w:document {}><w:body>
+            <w:p><w:pPr><w:pStyle w:val="Heading1"/></w:pPr><w:r><w:t>Report</w:t></w:r></w:p>
+            <w:p><w:r><w:t xml:space="preserve">Sales </w:t></w:r><w:r><w:t>grew.</w:t></w:r></w:p>
+            <w:p><w:pPr><w:pStyle w:val="Heading2"/></w:pPr><w:r><w:t>Figures</w:t></w:r></w:p>
+            <w:tbl><w:tr><w:tc><w:p><w:r><w:t>Q1</w:t></w:r></w:p></w:tc><w:tc><w:p><w:r><w:t>10</w:t></w:r></w:p></w:tc></w:tr></w:tbl>
+            <w:p><w:r><w:t>Line one</w:t><w:br/><w:t>line two</w:t></w:r></w:p>
+            </w:body></w:document>"#, W);
+        let mut writer = Writer::new();
+        DocxParser::body(&body, &styles, &mut writer).unwrap();
+        let document = writer.outline.into_document(BTreeMap::new());
+        let text = document.text();
+        assert!(text.contains("Sales grew.\n"));
+        assert!(text.contains("Q1  10\n"));
+        assert!(text.contains("Line one\nline two\n"));
+        assert_eq!(headings(&document), vec!["Report", "Report > Figures"]);
+    }
+
+    #[test]
+    fn xlsx_cells() {
+        let shared = XlsxParser::shared_strings(r#"<sst><si><t>Name</t></si><si><r><t>Ad</t></r><r><t>a</t></r><rPh><t>ruby</t></rPh></si></sst>"#).unwrap();
+        assert_eq!(shared, vec!["Name", "Ada"]);
+        let sheet = r#"<worksheet><sheetData>
+            <row><c t="s"><v>0</v></c><c><v>1.5</v></c></row>
+            <row><c t="s"><v>1</v></c><c t="b"><v>1</v></c><c t="inlineStr"><is><t>inline</t></is></c><c><f>SUM(A1)</f><v>3</v></c></row>
+            </sheetData></worksheet>"#;
+        let mut writer = Writer::new();
+        writer.page("Sheet1");
+        XlsxParser::sheet(sheet, &shared, &mut writer).unwrap();
+        let text = writer.outline.into_document(BTreeMap::new()).text();
+        assert_eq!(text, "Sheet1\n\nName  1.5\nAda  TRUE  inline  3\n");
+    }
+
+    #[test]
+    fn pptx_skips_repeated_placeholders() {
+        let slide = r#"<p:sld xmlns:p="p" xmlns:a="a"><p:cSld><p:spTree>
+            <p:sp><p:nvSpPr><p:nvPr><p:ph type="title"/></p:nvPr></p:nvSpPr><p:txBody><a:p><a:r><a:t>Roadmap</a:t></a:r></a:p></p:txBody></p:sp>
+            <p:sp><p:nvSpPr><p:nvPr><p:ph type="sldNum"/></p:nvPr></p:nvSpPr><p:txBody><a:p><a:r><a:t>7</a:t></a:r></a:p></p:txBody></p:sp>
+            <p:sp><p:txBody><a:p><a:r><a:t>Ship search</a:t></a:r></a:p></p:txBody></p:sp>
+            </p:spTree></p:cSld></p:sld>"#;
+        let mut writer = Writer::new();
+        PptxParser::text(slide, &mut writer).unwrap();
+        assert_eq!(writer.outline.into_document(BTreeMap::new()).text(), "Roadmap\nShip search\n");
+    }
+
+    #[test]
+    fn odf_text_and_spreadsheet() {
+        let text = r#"<office:document-content xmlns:office="o" xmlns:text="t"><office:body><office:text>
+            <text:h text:outline-level="1">Plan</text:h>
+            <text:p>One<text:s text:c="2"/>two<office:annotation><text:p>a comment</text:p></office:annotation></text:p>
+            <text:h text:outline-level="2">Details</text:h>
+            <text:p>More</text:p>
+            </office:text></office:body></office:document-content>"#;
+        let (mut writer, mut metadata) = (Writer::new(), BTreeMap::new());
+        OdfParser::content(text, &mut writer, &mut metadata).unwrap();
+        let document = writer.outline.into_document(metadata);
+        assert!(document.text().contains("One  two\n"));
+        assert!(!document.text().contains("a comment"));
+        assert_eq!(headings(&document), vec!["Plan", "Plan > Details"]);
+
+        let sheet = r#"<office:document-content xmlns:office="o" xmlns:table="t" xmlns:text="x"><office:body><office:spreadsheet>
+            <table:table table:name="Budget"><table:table-row><table:table-cell><text:p>Rent</text:p></table:table-cell><table:table-cell><text:p>900</text:p></table:table-cell></table:table-row></table:table>
+            </office:spreadsheet></office:body></office:document-content>"#;
+        let (mut writer, mut metadata) = (Writer::new(), BTreeMap::new());
+        OdfParser::content(sheet, &mut writer, &mut metadata).unwrap();
+        let document = writer.outline.into_document(metadata);
+        assert_eq!(document.metadata.get("sheets").map(String::as_str), Some("1"));
+        assert!(document.text().contains("Rent  900\n"));
+        assert_eq!(headings(&document), vec!["Budget"]);
+    }
+
+    #[test]
+    fn document_properties() {
+        let core = r#"<cp:coreProperties xmlns:cp="c" xmlns:dc="d"><dc:title>Q3 report</dc:title><dc:creator>Jane</dc:creator><dc:subject> </dc:subject></cp:coreProperties>"#;
+        let metadata = properties(core).unwrap();
+        assert_eq!(metadata.get("title").map(String::as_str), Some("Q3 report"));
+        assert_eq!(metadata.get("author").map(String::as_str), Some("Jane"));
+        assert!(!metadata.contains_key("subject"));
+
+        let meta = r#"<office:meta xmlns:meta="m" xmlns:dc="d"><meta:initial-creator>Ann</meta:initial-creator><dc:creator>Bob</dc:creator></office:meta>"#;
+        assert_eq!(properties(meta).unwrap().get("author").map(String::as_str), Some("Ann"));
+    }
+
+    #[test]
+    fn relationship_targets() {
+        assert_eq!(resolve("ppt/slides/slide1.xml", "../notesSlides/notesSlide1.xml"), "ppt/notesSlides/notesSlide1.xml");
+        assert_eq!(resolve("xl/workbook.xml", "worksheets/sheet1.xml"), "xl/worksheets/sheet1.xml");
+        assert_eq!(resolve("xl/workbook.xml", "/xl/worksheets/sheet2.xml"), "xl/worksheets/sheet2.xml");
+    }
+}
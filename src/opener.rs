@@ -0,0 +1,102 @@
+// Copyright: (c) 2023 Sureshkumar T
+// License: Apache-2.0
+
+// Opens search results where they matched: text files in the user's editor at
+// the line of the answer, PDFs in a viewer at the page of the passage, and
+// anything else with the desktop's default application.
+
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::process::Command;
+
+use crate::protocol;
+use crate::settings;
+
+fn system_opener() -> &'static str {
+    if cfg!(target_os = "macos") { "open" } else { "xdg-open" }
+}
+
+// Byte offset of the answer in the file.  The parsed document of a plain text
+// file is the file itself, so the chunk's offsets apply.  Markup is stripped
+// when parsing, so for those the answer is looked up in the file instead.
+fn answer_offset(result: &protocol::SearchResult, contents: &str) -> usize {
+    let in_chunk = result.chunk_text.find(&result.answer).unwrap_or(0);
+    if contents.get(result.start..result.end) == Some(result.chunk_text.as_str()) {
+        return result.start + in_chunk;
+    }
+    contents.find(&result.answer).unwrap_or(0)
+}
+
+// Line of the answer in the file, counting from 1
+fn answer_line(result: &protocol::SearchResult) -> usize {
+    match fs::read_to_string(&result.file) {
+        Ok(contents) => contents[..answer_offset(result, &contents)].matches('\n').count() + 1,
+        Err(_) => 1,
+    }
+}
+
+// Splits a command template into arguments and fills in the {file}, {line}
+// and {page} placeholders of each, so paths with spaces stay one argument
+fn command(template: &str, file: &str, line: usize, page: u32) -> Result<Command, Box<dyn Error>> {
+    let mut args = template.split_whitespace()
+        .map(|a| a.replace("{file}", file).replace("{line}", &line.to_string()).replace("{page}", &page.to_string()));
+    let program = match args.next() {
+        Some(program) => program,
+        None => return Err("empty open command".into()),
+    };
+    let mut command = Command::new(program);
+    command.args(args);
+    Ok(command)
+}
+
+// The configured editor, or $VISUAL or $EDITOR, which most editors accept a
+// +line argument for
+fn editor() -> Option<String> {
+    if let Some(editor) = &settings::get_config(None).editor {
+        return Some(editor.clone());
+    }
+    env::var("VISUAL").or_else(|_| env::var("EDITOR")).ok()
+        .filter(|e| e.trim().len() > 0)
+        .map(|e| format!("{} +{{line}} {{file}}", e))
+}
+
+pub fn open(result: &protocol::SearchResult) -> Result<(), Box<dyn Error>> {
+    let file = result.file.as_str();
+    let mime = mime_guess::from_path(file).first_or_octet_stream();
+    if mime == "application/pdf" {
+        let page = result.page.unwrap_or(1);
+        let template = settings::get_config(None).pdf_viewer.clone()
+            .unwrap_or(format!("{} {{file}}", system_opener()));
+        command(&template, file, 1, page)?.spawn()?;
+        return Ok(());
+    }
+    if mime.type_() == mime_guess::mime::TEXT {
+        if let Some(template) = editor() {
+            // terminal editors need the terminal until they exit
+            let status = command(&template, file, answer_line(result), 1)?.status()?;
+            if !status.success() {
+                return Err(format!("{} exited with {}", template, status).into());
+            }
+            return Ok(());
+        }
+    }
+    command(&format!("{} {{file}}", system_opener()), file, 1, 1)?.spawn()?;
+    Ok(())
+}
+
+// Remembers the results of a query in the order they were printed
+pub fn save_results(results: &[protocol::SearchResult]) {
+    let path = settings::get_last_results_file();
+    let saved = serde_json::to_string(results).map_err(|e| e.to_string())
+        .and_then(|json| fs::write(&path, json).map_err(|e| e.to_string()));
+    if let Err(e) = saved {
+        tracing::warn!("Could not save results to {}: {}", path, e);
+    }
+}
+
+pub fn load_results() -> Vec<protocol::SearchResult> {
+    fs::read_to_string(settings::get_last_results_file()).ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
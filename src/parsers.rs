@@ -1,68 +1,422 @@
 // Copyright: (c) 2023 Sureshkumar T
 // License: Apache-2.0
 
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
 use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
 use std::process::Command;
 use std::process::Stdio;
+use std::rc::Rc;
 use mime_guess;
 
-use pdf_extract;
+use lopdf;
+use pdf_extract::{ConvertToFmt, MediaBox, OutputDev, OutputError, PlainTextOutput, Transform};
 
 use crate::error::{FileNotFoundError, UnsupportedFileTypeError};
+use crate::chunker::Section;
+use crate::error;
+use crate::html;
+use crate::markup;
+use crate::office;
+use crate::settings;
 
-pub struct Parser {
+// The text of a parsed file.  Formats with pages keep the text of each page
+// separately.
+pub struct Document {
+    pub pages: Vec<String>,
+    // e.g. the title and author of a pdf
+    pub metadata: BTreeMap<String, String>,
+    // for formats with headings, by offset in text()
+    pub sections: Vec<Section>,
 }
 
-impl Parser {
-    pub fn new() -> Self {
-        Parser {}
+impl Document {
+    pub fn new(text: String) -> Self {
+        Document { pages: vec![text], metadata: BTreeMap::new(), sections: Vec::new() }
     }
 
-    pub fn parse(&self, filename: &str) -> Result<String, Box<dyn Error>> {
-        if !Path::new(filename).exists() {
-            return Err(Box::new(FileNotFoundError::new(filename)));
-        }
-        let mime = mime_guess::from_path(filename).first();
-        if mime.is_none() {
-            return Err(Box::new(UnsupportedFileTypeError::new(filename)));
-        }
-        if let Some(mime) = mime {
-            if "application/pdf" == mime {
-                return self.parse_pdf(filename);
-            } else if "text/plain" == mime {
-                return self.parse_text(filename);
+    // The pages separated by form feeds, which is how the chunker numbers them
+    pub fn text(&self) -> String {
+        self.pages.join("\x0c")
+    }
+}
+
+// Text written by pdf-extract, shared so it can be taken out page by page
+#[derive(Clone)]
+struct SharedText(Rc<RefCell<String>>);
+
+impl std::fmt::Write for SharedText {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.0.borrow_mut().push_str(s);
+        Ok(())
+    }
+}
+
+impl ConvertToFmt for SharedText {
+    type Writer = SharedText;
+    fn convert(self) -> Self::Writer {
+        self
+    }
+}
+
+// Lays out text like pdf-extract's plain text output, and cuts it into pages
+struct PageOutput {
+    text: SharedText,
+    plain: PlainTextOutput<SharedText>,
+    pages: Vec<String>,
+}
+
+impl PageOutput {
+    fn new() -> Self {
+        let text = SharedText(Rc::new(RefCell::new(String::new())));
+        PageOutput { plain: PlainTextOutput::new(text.clone()), text, pages: Vec::new() }
+    }
+}
+
+impl OutputDev for PageOutput {
+    fn begin_page(&mut self, page_num: u32, media_box: &MediaBox, art_box: Option<(f64, f64, f64, f64)>) -> Result<(), OutputError> {
+        self.plain.begin_page(page_num, media_box, art_box)
+    }
+    fn end_page(&mut self) -> Result<(), OutputError> {
+        self.plain.end_page()?;
+        let page = std::mem::take(&mut *self.text.0.borrow_mut());
+        self.pages.push(page);
+        Ok(())
+    }
+    fn output_character(&mut self, trm: &Transform, width: f64, spacing: f64, font_size: f64, char: &str) -> Result<(), OutputError> {
+        self.plain.output_character(trm, width, spacing, font_size, char)
+    }
+    fn begin_word(&mut self) -> Result<(), OutputError> {
+        self.plain.begin_word()
+    }
+    fn end_word(&mut self) -> Result<(), OutputError> {
+        self.plain.end_word()
+    }
+    fn end_line(&mut self) -> Result<(), OutputError> {
+        self.plain.end_line()
+    }
+}
+
+// Pdf text strings are UTF-16BE with a byte order mark, or PDFDocEncoding,
+// which matches latin1 for the characters that matter here
+fn pdf_string(bytes: &[u8]) -> String {
+    if bytes.len() >= 2 && bytes[0] == 0xfe && bytes[1] == 0xff {
+        let units = bytes[2..].chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect::<Vec<u16>>();
+        String::from_utf16_lossy(&units)
+    } else {
+        bytes.iter().map(|b| *b as char).collect()
+    }
+}
+
+// Title and author from the document information dictionary
+fn pdf_info(doc: &lopdf::Document) -> BTreeMap<String, String> {
+    let mut metadata = BTreeMap::new();
+    let info = match doc.trailer.get(b"Info") {
+        Ok(lopdf::Object::Reference(id)) => doc.get_object(*id).and_then(|o| o.as_dict()),
+        Ok(object) => object.as_dict(),
+        Err(e) => Err(e),
+    };
+    if let Ok(info) = info {
+        for (key, name) in [(&b"Title"[..], "title"), (&b"Author"[..], "author")] {
+            if let Ok(lopdf::Object::String(bytes, _)) = info.get(key) {
+                let value = pdf_string(bytes).trim().to_string();
+                if value.len() > 0 {
+                    metadata.insert(name.to_string(), value);
+                }
             }
         }
-        return Err(Box::new(UnsupportedFileTypeError::new(filename)));
     }
+    metadata
+}
 
-    pub fn parse_pdf(&self, filename: &str) -> Result<String, Box<dyn Error>> {
-        log::debug!("parsing pdf file: {}", filename);
+// Reads one kind of file.  Parsers are looked up in a Registry by the
+// file's extension, mime type or first bytes.
+pub trait DocumentParser: Send + Sync {
+    // what the [parser] configuration calls it
+    fn name(&self) -> &'static str;
+    // mime types, as guessed from the file name, and extensions it reads
+    // unless configured otherwise
+    fn mime_types(&self) -> &'static [&'static str];
+    fn extensions(&self) -> &'static [&'static str] {
+        &[]
+    }
+    // leading bytes of the files it reads, for names that give nothing away
+    fn magic(&self) -> &'static [&'static [u8]] {
+        &[]
+    }
+    fn parse(&self, filename: &str) -> Result<Document, Box<dyn Error>>;
+}
+
+pub struct TextParser;
+
+impl DocumentParser for TextParser {
+    fn name(&self) -> &'static str {
+        "text"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["text/plain"]
+    }
+
+    fn parse(&self, filename: &str) -> Result<Document, Box<dyn Error>> {
+        let mut file = File::open(filename)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        Ok(Document::new(contents))
+    }
+}
+
+pub struct PdfParser;
+
+impl PdfParser {
+    fn parse_native(&self, filename: &str) -> Result<Document, Box<dyn Error>> {
+        let doc = lopdf::Document::load(filename)?;
+        let mut output = PageOutput::new();
+        // pdf-extract panics on some malformed pdfs
+        let extracted = panic::catch_unwind(AssertUnwindSafe(|| pdf_extract::output_doc(&doc, &mut output)));
+        match extracted {
+            Ok(result) => result?,
+            Err(_) => return Err(Box::new(error::Error::new(&format!("pdf extraction panicked on {}", filename)))),
+        }
+        Ok(Document { pages: output.pages, metadata: pdf_info(&doc), sections: Vec::new() })
+    }
+
+    // Page boundaries are lost this way
+    fn parse_ghostscript(&self, filename: &str) -> Result<Document, Box<dyn Error>> {
         let mut pdf2ps = Command::new("pdf2ps")
             .arg(filename)
             .arg("-")
             .stdout(Stdio::piped())
             .spawn()
-            .expect("failed to execute process");
-        let pdf2ps_out = pdf2ps.stdout.expect("failed to get stdout");
-        let mut ps2ascii = Command::new("ps2ascii")
+            .map_err(|e| error::Error::new(&format!("cannot run pdf2ps: {}", e)))?;
+        let pdf2ps_out = pdf2ps.stdout.take().ok_or(error::Error::new("failed to get pdf2ps output"))?;
+        let ps2ascii = Command::new("ps2ascii")
             .stdin(Stdio::from(pdf2ps_out))
             .stdout(Stdio::piped())
             .spawn()
-            .expect("failed to execute process");
-        let output = ps2ascii.wait_with_output().expect("failed to wait on child");
+            .map_err(|e| error::Error::new(&format!("cannot run ps2ascii: {}", e)))?;
+        let output = ps2ascii.wait_with_output()?;
+        let _ = pdf2ps.wait();
         let output = String::from_utf8(output.stdout)?;
         log::debug!("output: {}", output.len());
-        Ok(output)
+        Ok(Document::new(output))
     }
+}
 
-    pub fn parse_text(&self, filename: &str) -> Result<String, Box<dyn Error>> {
-        let mut file = File::open(filename)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        Ok(contents)
+impl DocumentParser for PdfParser {
+    fn name(&self) -> &'static str {
+        "pdf"
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/pdf"]
+    }
+
+    fn magic(&self) -> &'static [&'static [u8]] {
+        &[b"%PDF-"]
+    }
+
+    // Extracts the text of each page in process, falling back to Ghostscript
+    // if configured to and the pdf can't be read
+    fn parse(&self, filename: &str) -> Result<Document, Box<dyn Error>> {
+        log::debug!("parsing pdf file: {}", filename);
+        let native = self.parse_native(filename);
+        match native {
+            Err(e) if settings::get_config(None).pdf_fallback == "ghostscript" => {
+                log::debug!("extracting {} failed, trying ghostscript: {}", filename, e);
+                self.parse_ghostscript(filename)
+            }
+            native => native,
+        }
+    }
+}
+
+// The parsers semdesk comes with
+fn builtin() -> Vec<Box<dyn DocumentParser>> {
+    vec![
+        Box::new(TextParser), Box::new(PdfParser),
+        Box::new(markup::MarkdownParser), Box::new(markup::RstParser), Box::new(markup::OrgParser),
+        Box::new(html::HtmlParser),
+        Box::new(office::DocxParser), Box::new(office::XlsxParser), Box::new(office::PptxParser), Box::new(office::OdfParser),
+    ]
+}
+
+// Bytes read from the start of a file to match magic numbers against
+const MAGIC_BYTES: usize = 16;
+
+// Picks the parser for a file by its extension, then the mime type guessed
+// from its name, then its first bytes.  Extensions and mime types map to
+// parser names, or to "none" to leave those files alone.
+pub struct Registry {
+    parsers: Vec<Box<dyn DocumentParser>>,
+    by_extension: HashMap<String, String>,
+    by_mime: HashMap<String, String>,
+    disabled: HashSet<String>,
+}
+
+impl Registry {
+    pub fn new(parsers: Vec<Box<dyn DocumentParser>>) -> Self {
+        let mut by_extension = HashMap::new();
+        let mut by_mime = HashMap::new();
+        for parser in parsers.iter() {
+            for extension in parser.extensions() {
+                by_extension.insert(extension.to_string(), parser.name().to_string());
+            }
+            for mime in parser.mime_types() {
+                by_mime.insert(mime.to_string(), parser.name().to_string());
+            }
+        }
+        Registry { parsers, by_extension, by_mime, disabled: HashSet::new() }
+    }
+
+    // The built in parsers with the [parser] configuration applied
+    pub fn from_settings() -> Self {
+        let conf = settings::get_config(None);
+        let mut registry = Registry::new(builtin());
+        for name in conf.parsers_disabled.iter() {
+            registry.disabled.insert(name.clone());
+        }
+        for (extension, name) in conf.parser_extensions.iter() {
+            let extension = extension.trim_start_matches('.').to_lowercase();
+            registry.by_extension.insert(extension, name.clone());
+        }
+        for (mime, name) in conf.parser_mime_types.iter() {
+            registry.by_mime.insert(mime.to_lowercase(), name.clone());
+        }
+        for name in registry.by_extension.values().chain(registry.by_mime.values()).chain(registry.disabled.iter()) {
+            if name != "none" && registry.get(name).is_none() {
+                log::warn!("Unknown parser {} in the [parser] configuration", name);
+            }
+        }
+        registry
+    }
+
+    fn get(&self, name: &str) -> Option<&dyn DocumentParser> {
+        self.parsers.iter().find(|p| p.name() == name).map(|p| p.as_ref())
+    }
+
+    // The parser called `name`, or why it can't be used for `what`
+    fn usable(&self, name: &str, what: &str) -> Result<&dyn DocumentParser, String> {
+        if name == "none" {
+            return Err(format!("parsing {} files is turned off", what));
+        }
+        if self.disabled.contains(name) {
+            return Err(format!("the {} parser is disabled", name));
+        }
+        self.get(name).ok_or(format!("unknown parser {} configured for {}", name, what))
+    }
+
+    fn sniff(&self, filename: &str) -> Option<&dyn DocumentParser> {
+        let mut head = Vec::with_capacity(MAGIC_BYTES);
+        File::open(filename).ok()?.take(MAGIC_BYTES as u64).read_to_end(&mut head).ok()?;
+        self.parsers.iter()
+            .find(|p| p.magic().iter().any(|m| head.starts_with(m)))
+            .map(|p| p.as_ref())
+    }
+
+    // The parser for the file, or why there is none
+    pub fn find(&self, filename: &str) -> Result<&dyn DocumentParser, String> {
+        let extension = Path::new(filename).extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+        if let Some(extension) = extension {
+            if let Some(name) = self.by_extension.get(&extension) {
+                return self.usable(name, &format!(".{}", extension));
+            }
+        }
+        let mime = mime_guess::from_path(filename).first();
+        if let Some(mime) = &mime {
+            if let Some(name) = self.by_mime.get(mime.essence_str()) {
+                return self.usable(name, mime.essence_str());
+            }
+        }
+        if let Some(parser) = self.sniff(filename) {
+            return self.usable(parser.name(), parser.name());
+        }
+        match mime {
+            Some(mime) => Err(format!("no parser for {}", mime)),
+            None => Err(String::from("unknown file type")),
+        }
+    }
+
+    pub fn parse(&self, filename: &str) -> Result<Document, Box<dyn Error>> {
+        if !Path::new(filename).exists() {
+            return Err(Box::new(FileNotFoundError::new(filename)));
+        }
+        match self.find(filename) {
+            Ok(parser) => parser.parse(filename),
+            Err(reason) => Err(Box::new(UnsupportedFileTypeError::new(filename, &reason))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An empty file named `name` with `head` as its first bytes
+    fn scratch_file(name: &str, head: &[u8]) -> String {
+        let dir = std::env::temp_dir().join(format!("semdesk-parsers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, head).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn found(registry: &Registry, filename: &str) -> Result<&'static str, String> {
+        registry.find(filename).map(|p| p.name())
+    }
+
+    #[test]
+    fn finds_parser_by_mime_type() {
+        let registry = Registry::new(builtin());
+        assert_eq!(found(&registry, "/notes/todo.txt"), Ok("text"));
+        assert_eq!(found(&registry, "/papers/paper.PDF"), Ok("pdf"));
+    }
+
+    #[test]
+    fn extension_comes_before_mime_type() {
+        let mut registry = Registry::new(builtin());
+        registry.by_extension.insert(String::from("txt"), String::from("markdown"));
+        assert_eq!(found(&registry, "/notes/todo.txt"), Ok("markdown"));
+    }
+
+    #[test]
+    fn mime_type_comes_before_magic() {
+        let mut registry = Registry::new(builtin());
+        let filename = scratch_file("looks-like-pdf.txt", b"%PDF-1.4\n");
+        assert_eq!(found(&registry, &filename), Ok("text"));
+        registry.by_mime.remove("text/plain");
+        assert_eq!(found(&registry, &filename), Ok("pdf"));
+    }
+
+    #[test]
+    fn sniffs_files_without_extension() {
+        let registry = Registry::new(builtin());
+        assert_eq!(found(&registry, &scratch_file("scan", b"%PDF-1.7\n")), Ok("pdf"));
+        assert_eq!(found(&registry, &scratch_file("blob", b"\x00\x01\x02")), Err(String::from("unknown file type")));
+    }
+
+    #[test]
+    fn none_turns_parsing_off() {
+        let mut registry = Registry::new(builtin());
+        registry.by_extension.insert(String::from("pdf"), String::from("none"));
+        assert_eq!(found(&registry, "/papers/paper.pdf"), Err(String::from("parsing .pdf files is turned off")));
+    }
+
+    #[test]
+    fn disabled_parsers_are_not_used() {
+        let mut registry = Registry::new(builtin());
+        registry.disabled.insert(String::from("pdf"));
+        assert_eq!(found(&registry, "/papers/paper.pdf"), Err(String::from("the pdf parser is disabled")));
+    }
+
+    #[test]
+    fn unknown_parser_names_are_reported() {
+        let mut registry = Registry::new(builtin());
+        registry.by_mime.insert(String::from("text/plain"), String::from("plain"));
+        assert_eq!(found(&registry, "/notes/todo.txt"), Err(String::from("unknown parser plain configured for text/plain")));
     }
 }
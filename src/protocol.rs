@@ -0,0 +1,332 @@
+// Copyright: (c) 2023 Sureshkumar T
+// License: Apache-2.0
+
+// Messages exchanged between semdesk-cli and the daemon over the unix socket.
+// Each message is one JSON object on its own line.  The client sends a
+// request and reads responses, all carrying the request's id, until one of
+// type `done` or `error`.  It may then send another request on the same
+// connection, or close it.
+//
+// A query is answered in stages so clients can show something right away:
+// a `hits` response with the retrieved passages, a `results` response with
+// the answers from each passage as the question answering model gets to it,
+// and finally `done`.
+
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::io::{BufRead, Read, Write};
+
+use crate::error::Error;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Requests larger than this are rejected rather than read into memory
+pub const MAX_REQUEST_BYTES: u64 = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Request {
+    pub version: u32,
+    pub id: String,
+    #[serde(flatten)]
+    pub body: RequestBody,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RequestBody {
+    Query {
+        query: String,
+        #[serde(default)]
+        options: QueryOptions,
+    },
+    // the parsed contents of a catalogued file, by chunk id or path.  The
+    // chunk id has its own name as the request id is in the same object.
+    Document {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chunk_id: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+    },
+    Status,
+    // index a file or directory now, drop one from the catalog and index, or
+    // re-embed one (everything in the catalog if no path is given).  Paths
+    // must be absolute.
+    Add { path: String },
+    Remove { path: String },
+    Reindex {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+    },
+    // catalogued and skipped files below a path prefix, and one file in detail
+    List {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prefix: Option<String>,
+    },
+    Show { path: String },
+}
+
+// Overrides of the daemon's retrieval settings for one query
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct QueryOptions {
+    // semantic, lexical or hybrid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_score: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nprobe: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ef_search: Option<u32>,
+    #[serde(default)]
+    pub filters: Filters,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Filters {
+    // only search files below this path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_prefix: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Response {
+    pub version: u32,
+    pub id: String,
+    #[serde(flatten)]
+    pub body: ResponseBody,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseBody {
+    Hits { hits: Vec<Passage> },
+    Results { results: Vec<SearchResult> },
+    Done { count: usize },
+    Document { contents: Vec<String> },
+    Status { status: StatusReport },
+    // one per file handled by an add, remove or reindex request, then a summary
+    Progress {
+        file: String,
+        // indexed, unchanged, moved, removed, skipped or failed
+        action: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+    },
+    // a batch of a listing, which ends with `done`
+    Files { files: Vec<FileInfo> },
+    File { file: FileInfo, chunks: Vec<ChunkInfo> },
+    Summary {
+        indexed: usize,
+        unchanged: usize,
+        removed: usize,
+        skipped: usize,
+        failed: usize,
+    },
+    Error { message: String },
+}
+
+// A passage found by retrieval, before any answer is extracted from it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Passage {
+    pub file: String,
+    pub chunk_id: u64,
+    pub score: f32,
+    pub chunk_text: String,
+    pub start: usize,
+    pub end: usize,
+    pub char_start: usize,
+    pub char_end: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<u32>,
+    // headings of the section of the document, outermost first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub section: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchResult {
+    pub file: String,
+    pub chunk_id: u64,
+    pub answer: String,
+    // confidence of the answer, and similarity of the chunk to the query
+    pub score: f32,
+    pub retrieval_score: f32,
+    pub chunk_text: String,
+    // byte and character offsets of the chunk in the parsed document
+    pub start: usize,
+    pub end: usize,
+    pub char_start: usize,
+    pub char_end: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<u32>,
+    // headings of the section of the document, outermost first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub section: Vec<String>,
+}
+
+// What the daemon has indexed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusReport {
+    // catalogued files and the vectors in the faiss index
+    pub files: usize,
+    pub vectors: u64,
+    pub index_factory: String,
+    // rfc3339 time of the last full scan
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_scan: Option<String>,
+    // changed files and add, remove or reindex requests waiting to be handled
+    pub queue_depth: usize,
+    // files the crawler skipped, by reason
+    pub skipped: BTreeMap<String, usize>,
+    pub embedding_model: String,
+    pub qa_model: String,
+    pub db_bytes: u64,
+    pub index_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileInfo {
+    pub path: String,
+    // "indexed", or why the crawler skipped the file
+    pub status: String,
+    // what went wrong with a skipped file, e.g. the parse error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub vector_ids: Vec<u64>,
+    pub chunks: usize,
+    // rfc3339 time the file was last embedded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub indexed_at: Option<String>,
+    pub size: u64,
+    pub mime: String,
+    pub hash: String,
+    // e.g. title and author
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkInfo {
+    pub id: u64,
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<u32>,
+    // headings of the section of the document, outermost first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub section: Vec<String>,
+}
+
+impl Request {
+    pub fn new(id: String, body: RequestBody) -> Self {
+        Request { version: PROTOCOL_VERSION, id, body }
+    }
+}
+
+impl Response {
+    pub fn new(id: &str, body: ResponseBody) -> Self {
+        Response { version: PROTOCOL_VERSION, id: id.to_string(), body }
+    }
+
+    pub fn error(id: &str, message: &str) -> Self {
+        Response::new(id, ResponseBody::Error { message: message.to_string() })
+    }
+}
+
+pub fn write_message<T: Serialize, W: Write>(writer: &mut W, message: &T) -> Result<(), Box<dyn StdError>> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Reads one line of at most MAX_REQUEST_BYTES.  Returns None at end of stream.
+pub fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>, Box<dyn StdError>> {
+    let mut line = String::new();
+    let n = reader.by_ref().take(MAX_REQUEST_BYTES).read_line(&mut line)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') && n as u64 == MAX_REQUEST_BYTES {
+        return Err(Box::new(Error::new("message too large")));
+    }
+    Ok(Some(line))
+}
+
+pub fn parse_request(line: &str) -> Result<Request, Box<dyn StdError>> {
+    let request: Request = serde_json::from_str(line)?;
+    if request.version != PROTOCOL_VERSION {
+        return Err(Box::new(Error::new(&format!("unsupported protocol version {}, expected {}", request.version, PROTOCOL_VERSION))));
+    }
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn requests_round_trip() {
+        let options = QueryOptions { mode: Some(String::from("lexical")), top_k: Some(5), min_score: Some(0.25), nprobe: Some(8), ef_search: Some(64), filters: Filters { path_prefix: Some(String::from("/home/me/notes")) } };
+        let bodies = vec![
+            RequestBody::Query { query: String::from("where is the invoice"), options },
+            RequestBody::Query { query: String::from("defaults"), options: QueryOptions::default() },
+            RequestBody::Document { chunk_id: Some(42), path: None },
+            RequestBody::Document { chunk_id: None, path: Some(String::from("/home/me/a.txt")) },
+            RequestBody::Status,
+            RequestBody::Add { path: String::from("/home/me/new") },
+            RequestBody::Remove { path: String::from("/home/me/old") },
+            RequestBody::Reindex { path: Some(String::from("/home/me/notes")) },
+            RequestBody::Reindex { path: None },
+            RequestBody::List { prefix: Some(String::from("/home/me")) },
+            RequestBody::List { prefix: None },
+            RequestBody::Show { path: String::from("/home/me/a.txt") },
+        ];
+        let mut wire = Vec::new();
+        for (i, body) in bodies.iter().enumerate() {
+            write_message(&mut wire, &Request::new(format!("r{}", i), body.clone())).unwrap();
+        }
+        let mut reader = Cursor::new(wire);
+        for (i, body) in bodies.iter().enumerate() {
+            let line = read_line(&mut reader).unwrap().unwrap();
+            let request = parse_request(&line).unwrap();
+            assert_eq!(request.id, format!("r{}", i));
+            assert_eq!(serde_json::to_value(&request.body).unwrap(), serde_json::to_value(body).unwrap());
+        }
+        assert!(read_line(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let e = parse_request(r#"{"version": 2, "id": "a", "type": "status"}"#).unwrap_err();
+        assert!(e.to_string().contains("unsupported protocol version 2, expected 1"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert!(parse_request("{\"version\": 1, \"id\": \"a\", \"type\": ").is_err());
+        assert!(parse_request(r#"{"version": 1, "id": "a"}"#).is_err());
+        assert!(parse_request(r#"{"version": 1, "id": "a", "type": "launch"}"#).is_err());
+        assert!(parse_request(r#"{"version": 1, "id": "a", "type": "add"}"#).is_err());
+    }
+
+    #[test]
+    fn limits_line_length() {
+        let mut fits = vec![b'a'; MAX_REQUEST_BYTES as usize - 1];
+        fits.push(b'\n');
+        let line = read_line(&mut Cursor::new(fits)).unwrap().unwrap();
+        assert_eq!(line.len() as u64, MAX_REQUEST_BYTES);
+
+        let mut oversize = vec![b'a'; MAX_REQUEST_BYTES as usize];
+        oversize.push(b'\n');
+        let e = read_line(&mut Cursor::new(oversize)).unwrap_err();
+        assert_eq!(e.to_string(), Error::new("message too large").to_string());
+    }
+}
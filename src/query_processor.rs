@@ -2,14 +2,19 @@
 // License: Apache-2.0
 
 
-use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Sender, Receiver, channel};
-use rust_bert::pipelines::question_answering::{QuestionAnsweringModel, QaInput};
-use std::os::unix::net::{UnixStream,UnixListener};
-use std::io::{Read, Write};
+use rust_bert::pipelines::question_answering::{QuestionAnsweringModel, QuestionAnsweringConfig, QaInput};
+use rust_bert::pipelines::common::ModelType;
+use rust_bert::resources::LocalResource;
+use std::error::Error as StdError;
+use std::path::PathBuf;
 
 use crate::retriever;
 use crate::settings;
+use crate::error::Error;
+use crate::protocol;
 
 #[derive(Debug)]
 #[derive(Clone)]
@@ -18,15 +23,51 @@ pub struct DocResult {
     pub loc: String,
     pub text: String,
     pub score: f32,
+    // the chunk the answer was found in and its similarity to the query
+    pub chunk_id: u64,
+    pub retrieval_score: f32,
+    pub chunk_text: String,
+    pub start: usize,
+    pub end: usize,
+    pub char_start: usize,
+    pub char_end: usize,
+    pub page: Option<u32>,
+    pub section: Vec<String>,
 }
 
 impl DocResult {
-    fn new(id: String, loc: String, text: String, score: f32) -> Self {
+    fn from_hit(hit: &retriever::Hit, text: String, score: f32) -> Self {
         DocResult {
-            id,
-            loc,
+            id: hit.id.to_string(),
+            loc: hit.filename.clone(),
             text,
             score,
+            chunk_id: hit.id,
+            retrieval_score: hit.score,
+            chunk_text: hit.chunk.text.clone(),
+            start: hit.chunk.start,
+            end: hit.chunk.end,
+            char_start: hit.chunk.char_start,
+            char_end: hit.chunk.char_end,
+            page: hit.chunk.page,
+            section: hit.chunk.section.clone(),
+        }
+    }
+
+    pub fn to_result(&self) -> protocol::SearchResult {
+        protocol::SearchResult {
+            file: self.loc.clone(),
+            chunk_id: self.chunk_id,
+            answer: self.text.clone(),
+            score: self.score,
+            retrieval_score: self.retrieval_score,
+            chunk_text: self.chunk_text.clone(),
+            start: self.start,
+            end: self.end,
+            char_start: self.char_start,
+            char_end: self.char_end,
+            page: self.page,
+            section: self.section.clone(),
         }
     }
 }
@@ -38,52 +79,131 @@ impl std::fmt::Display for DocResult {
 }
 
 
+// Work for the question answering model.  Connections queue one message per
+// retrieved passage; passages of a cancelled query are skipped.
+pub enum Message {
+    Answer(String, retriever::Hit, Arc<AtomicBool>, Sender<Vec<DocResult>>),
+}
+
 pub trait QueryProcessor {
-    fn new(retriever: Sender<retriever::Message>) -> Self;
-    fn process(&mut self, query: &str, context: String) -> Vec<DocResult>;
+    // fails if the question answering model can't be loaded
+    fn new() -> Result<Self, Box<dyn StdError>> where Self: Sized;
+    fn process(&mut self, query: &str, hit: &retriever::Hit) -> Vec<DocResult>;
     fn run(&mut self);
 }
 
 pub struct QueryProcessorImpl {
     qa_model: QuestionAnsweringModel,
-    retriever: Sender<retriever::Message>,
-    comm: (Sender<(String, Sender<(String, Vec<DocResult>)>)>, Receiver<(String, Sender<(String, Vec<DocResult>)>)>),
-    listener: UnixListener,
+    comm: (Sender<Message>, Receiver<Message>),
+}
+
+
+// Reads the transformer type of a local model from its config.json
+fn local_model_type(model_dir: &PathBuf) -> Result<ModelType, Box<dyn StdError>> {
+    let config: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(model_dir.join("config.json"))?)?;
+    match config["model_type"].as_str() {
+        Some("bert") => Ok(ModelType::Bert),
+        Some("distilbert") => Ok(ModelType::DistilBert),
+        Some("roberta") => Ok(ModelType::Roberta),
+        other => Err(Box::new(Error::new(&format!("unsupported question answering model type {:?} in {:?}", other, model_dir)))),
+    }
+}
+
+// Checks that a local question answering model directory has its weights,
+// config and vocabulary.
+pub fn validate_qa_model(model_dir: &str) -> Result<(), Box<dyn StdError>> {
+    let dir = PathBuf::from(model_dir);
+    if !dir.is_dir() {
+        return Err(Box::new(Error::new(&format!("question answering model directory {} does not exist", model_dir))));
+    }
+    let mut missing = Vec::new();
+    for file in ["config.json", "rust_model.ot"] {
+        if !dir.join(file).exists() {
+            missing.push(file);
+        }
+    }
+    if missing.len() == 0 {
+        match local_model_type(&dir)? {
+            ModelType::Roberta => {
+                for file in ["vocab.json", "merges.txt"] {
+                    if !dir.join(file).exists() {
+                        missing.push(file);
+                    }
+                }
+            }
+            _ => {
+                if !dir.join("vocab.txt").exists() {
+                    missing.push("vocab.txt");
+                }
+            }
+        }
+    }
+    if missing.len() > 0 {
+        return Err(Box::new(Error::new(&format!("question answering model directory {} is missing {}", model_dir, missing.join(", ")))));
+    }
+    Ok(())
 }
 
+// Name of the question answering model, as reported by status
+pub fn qa_model_id() -> String {
+    match settings::get_qa_path() {
+        Some(path) => path,
+        None => String::from("distilbert-base-cased-distilled-squad"),
+    }
+}
+
+// Loads the question answering model from `model.qa_path` if set, otherwise
+// the default distilbert model fine tuned on SQuAD, downloaded on first use.
+fn load_qa_model() -> Result<QuestionAnsweringModel, Box<dyn StdError>> {
+    let model_dir = match settings::get_qa_path() {
+        Some(path) => PathBuf::from(path),
+        None => return Ok(QuestionAnsweringModel::new(Default::default())?),
+    };
+    let model_type = local_model_type(&model_dir)?;
+    let (vocab, merges) = match model_type {
+        ModelType::Roberta => (model_dir.join("vocab.json"), Some(LocalResource::from(model_dir.join("merges.txt")))),
+        _ => (model_dir.join("vocab.txt"), None),
+    };
+    let config = QuestionAnsweringConfig::new(
+        model_type,
+        LocalResource::from(model_dir.join("rust_model.ot")),
+        LocalResource::from(model_dir.join("config.json")),
+        LocalResource::from(vocab),
+        merges,
+        model_type != ModelType::Roberta,
+        None,
+        model_type == ModelType::Roberta,
+    );
+    log::info!("Loading question answering model from {:?}", model_dir);
+    Ok(QuestionAnsweringModel::new(config)?)
+}
 
 impl QueryProcessorImpl {
-    fn new(retriever: Sender<retriever::Message>) -> Self {
-        let sock_path = settings::get_socket_path();
-        let listener = UnixListener::bind(sock_path).unwrap();
-        listener.set_nonblocking(true).unwrap();
-
-        QueryProcessorImpl {
-            qa_model: QuestionAnsweringModel::new(Default::default()).unwrap(),
-            retriever,
+    fn new() -> Result<Self, Box<dyn StdError>> {
+        Ok(QueryProcessorImpl {
+            qa_model: load_qa_model()?,
             comm: channel(),
-            listener: listener,
-        }
+        })
     }
-    pub fn get_query_channel(&self) -> Sender<(String, Sender<(String, Vec<DocResult>)>)> {
+    pub fn get_query_channel(&self) -> Sender<Message> {
         self.comm.0.clone()
     }
 }
 
 impl QueryProcessor for QueryProcessorImpl {
-    fn new(retriever: Sender<retriever::Message>) -> Self {
-        QueryProcessorImpl::new(retriever)
+    fn new() -> Result<Self, Box<dyn StdError>> {
+        QueryProcessorImpl::new()
     }
 
-    fn process(&mut self, query: &str, context: String) -> Vec<DocResult> {
+    fn process(&mut self, query: &str, hit: &retriever::Hit) -> Vec<DocResult> {
         let mut results = Vec::new();
-        let answers = self.qa_model.predict(&[QaInput { question: String::from(query), context: String::from(context) }], 3, 32);
+        let answers = self.qa_model.predict(&[QaInput { question: String::from(query), context: hit.context.clone() }], 3, 32);
         log::debug!("{:?}", answers);
         if answers.len() == 0 {
             return results;
         }
         for answer in answers[0].iter() {
-            let result = DocResult::new(String::from("id"), String::from("loc"), answer.answer.clone(), answer.score as f32);
+            let result = DocResult::from_hit(hit, answer.answer.clone(), answer.score as f32);
             results.push(result);
         }
         results
@@ -91,83 +211,21 @@ impl QueryProcessor for QueryProcessorImpl {
 
     fn run(&mut self) {
         loop {
-            if let Ok((msg, ch)) = self.comm.1.try_recv() {
-                log::debug!("QueryProcessor received: {}", msg);
-                let (sender, receiver) = channel();
-                self.retriever.send(retriever::Message::RetrieveByQuery(msg.clone(), sender)).unwrap();
-                if let Ok(docs) = receiver.recv() {
-                    match docs {
-                        retriever::Reply::Docs(docs) => {
-                            for (filename,doc) in docs.iter() {
-                                log::debug!("doc: {}", doc.len());
-                                let mut results: Vec<DocResult> = self.process(&msg, doc.to_string());
-                                let sent = ch.send((filename.clone(), results));
-                                if sent.is_err() {
-                                    log::error!("Error sending results: {}", sent.err().unwrap());
-                                    break;
-                                }
-                            }
-                        },
-                        _ => {
-                            log::debug!("No docs found");
-                        }
-                    }
-                }
-
-            }
-
-            let s = self.listener.accept();
-            if let Ok((mut stream, _)) = s {
-                    let mut buf = [0; 1024];
-                    let chan = stream.read(&mut buf);
-                    if chan.is_err() {
-                        log::error!("Error reading from socket: {}", chan.err().unwrap());
-                        return;
+            let msg = match self.comm.1.recv() {
+                Ok(msg) => msg,
+                Err(_) => return,
+            };
+            match msg {
+                Message::Answer(query, hit, cancel, reply) => {
+                    if cancel.load(Ordering::Relaxed) {
+                        continue;
                     }
-                    let n = chan.unwrap();
-                    if n > 0 {
-                        let msg = String::from_utf8_lossy(&buf[..n]);
-                        log::debug!("QueryProcessor received: {}", msg);
-                        let (sender, receiver) = channel();
-                        self.retriever.send(retriever::Message::RetrieveByQuery(msg.clone().to_string(), sender)).unwrap();
-                        if let Ok(docs) = receiver.recv() {
-                            match docs {
-                                retriever::Reply::Docs(docs) => {
-                                    for (filename,doc) in docs.iter() {
-                                        log::debug!("doc2: {}", doc.len());
-                                        let mut results: Vec<DocResult> = self.process(&msg, doc.to_string());
-                                        let mut res = String::new();
-                                        for result in results.iter() {
-                                            // remove unprintable characters
-                                            let mut text = result.text.replace("\n", " ");
-                                            text = text.replace("\r", " ");
-                                            text = text.replace("|", " ");
-                                            res.push_str(&format!("{}|{}|{}|{}\n", result.id, filename, text, result.score));
-                                        }
-                                        let res = stream.write(res.as_bytes());
-                                        if res.is_err() {
-                                            log::error!("Error writing to socket: {}", res.err().unwrap());
-                                            return;
-                                        }
-                                    }
-                                },
-                                _ => {
-                                    log::debug!("No docs found");
-                                }
-                            }
-                        }
-                        stream.flush().unwrap();
-                        if let Err(e) = stream.shutdown(std::net::Shutdown::Both) {
-                            log::error!("Error shutting down socket: {}", e);
-                        }
-                    }
-            } else if let Err(e) = s {
-                if e.kind() != std::io::ErrorKind::WouldBlock {
-                    log::error!("Error accepting socket: {}", e);
+                    log::debug!("hit: {} {}", hit.id, hit.context.len());
+                    let results = self.process(&query, &hit);
+                    // the connection may have gone away meanwhile
+                    let _ = reply.send(results);
                 }
             }
-
-            thread::sleep(std::time::Duration::from_millis(100));
         }
     }
 }
@@ -7,29 +7,97 @@ use std::collections::HashSet;
 use std::error::Error as StdError;
 use std::sync::Arc;
 
-use crate::parsers::Parser;
+use crate::parsers::Registry;
 use crate::error::Error;
 use crate::catalog::Catalog;
 use crate::indexer;
 use crate::catalog;
+use crate::chunker::Chunk;
+use crate::settings;
 
 
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchMode {
+    Semantic,
+    Lexical,
+    Hybrid,
+}
+
+impl SearchMode {
+    pub fn from_str(mode: &str) -> Option<SearchMode> {
+        match mode {
+            "semantic" => Some(SearchMode::Semantic),
+            "lexical" => Some(SearchMode::Lexical),
+            "hybrid" => Some(SearchMode::Hybrid),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryOptions {
+    pub mode: SearchMode,
+    pub search: indexer::SearchParams,
+    // only return hits in files below this path
+    pub path_prefix: Option<String>,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        let mode = SearchMode::from_str(&settings::get_config(None).search_mode).unwrap_or(SearchMode::Hybrid);
+        QueryOptions { mode, search: indexer::SearchParams::default(), path_prefix: None }
+    }
+}
+
+// Filtered queries look at this many times more candidates, as many of the
+// nearest chunks may be in files outside the filter
+const FILTER_OVERFETCH: usize = 4;
+// Constant of reciprocal rank fusion, damping the weight of the top ranks
+const RRF_K: f32 = 60.0;
+
+// Merges rankings by summing 1 / (RRF_K + rank) of each id over the rankings
+// it appears in.  Scores of different retrievers aren't comparable, ranks are.
+pub fn reciprocal_rank_fusion(rankings: &Vec<Vec<(u64, f32)>>, k: usize) -> Vec<(u64, f32)> {
+    let mut fused: HashMap<u64, f32> = HashMap::new();
+    for ranking in rankings {
+        for (rank, (id, _)) in ranking.iter().enumerate() {
+            *fused.entry(*id).or_insert(0.0) += 1.0 / (RRF_K + rank as f32 + 1.0);
+        }
+    }
+    let mut fused = fused.into_iter().collect::<Vec<(u64, f32)>>();
+    fused.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    fused.truncate(k);
+    fused
+}
+
 #[derive(Debug)]
 pub enum Message {
-    RetrieveByQuery(String, Sender<Reply>),
+    RetrieveByQuery(String, QueryOptions, Sender<Reply>),
     RetrieveByPath(String, Sender<Vec<String>>),
     RetrieveById(u64, Sender<Vec<String>>),
 }
 
 pub enum Reply {
-    Docs(Vec<(String, String)>),
+    Docs(Vec<Hit>),
+    Failed(String),
+}
+
+// A chunk matching a query.  The context is the chunk's text together with
+// that of its neighbouring chunks, which is what the answer is extracted from.
+#[derive(Debug, Clone)]
+pub struct Hit {
+    pub filename: String,
+    pub id: u64,
+    pub score: f32,
+    pub chunk: Chunk,
+    pub context: String,
 }
 
 
 pub trait Retriever {
     fn new(catalog: Arc<Catalog>, indexer_channel: Sender<indexer::Message>) -> Self;
-    fn retrieve(&self, query: String) -> Vec<(String,String)>;
+    fn retrieve(&self, query: String, options: &QueryOptions) -> Result<Vec<Hit>, String>;
     fn retrieve_by_path(&self, path: String) -> Vec<String>;
     fn retrieve_by_id(&self, id: u64) -> Vec<String>;
     fn run(&mut self);
@@ -39,6 +107,7 @@ pub struct RetrieverImpl {
     retriever_channel: (Sender<Message>, Receiver<Message>),
     indexer_channel: Sender<indexer::Message>,
     catalog: Arc<Catalog>,
+    parsers: Registry,
 }
 
 impl RetrieverImpl {
@@ -47,6 +116,7 @@ impl RetrieverImpl {
             retriever_channel: channel(),
             indexer_channel,
             catalog,
+            parsers: Registry::from_settings(),
         }
     }
 
@@ -54,6 +124,70 @@ impl RetrieverImpl {
         self.retriever_channel.0.clone()
     }
 
+    fn retrieve_semantic(&self, query: String, params: &indexer::SearchParams) -> Result<Vec<(u64, f32)>, String> {
+        let ch = channel();
+        self.indexer_channel.send(indexer::Message::RetrieveDocument(query, params.clone(), ch.0)).unwrap();
+        match ch.1.recv() {
+            Ok(indexer::Reply::Docs(ids_scores)) => Ok(ids_scores),
+            Ok(indexer::Reply::Failed(e)) => Err(e),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    // Builds the hit for vector `id` of the catalogued file.  Entries indexed
+    // before chunks were stored have no chunk text and are skipped until the
+    // file is re-indexed.
+    fn make_hit(&self, entry: &catalog::Entry, id: u64, score: f32) -> Option<Hit> {
+        let chunk = match self.catalog.get_chunk(id) {
+            Ok(chunk) => chunk,
+            Err(_) => {
+                log::debug!("Retriever: no chunk stored for {} in {}", id, entry.filename);
+                return None;
+            }
+        };
+        // vector ids are stored in document order, so the neighbours of a
+        // chunk are the ones next to it in the entry
+        let window = settings::get_config(None).context_chunks;
+        let pos = entry.indexes.iter().position(|i| *i == id).unwrap_or(0);
+        let first = pos.saturating_sub(window);
+        let last = (pos + window).min(entry.indexes.len().saturating_sub(1));
+
+        let mut context = String::new();
+        let mut context_end = 0;
+        for i in first..=last {
+            let neighbour = if i == pos {
+                chunk.clone()
+            } else {
+                match self.catalog.get_chunk(entry.indexes[i]) {
+                    Ok(c) => c,
+                    Err(_) => continue,
+                }
+            };
+            // consecutive chunks overlap by a few sentences; skip the part
+            // already in the context
+            if context.len() > 0 && neighbour.start < context_end {
+                let skip = context_end - neighbour.start;
+                if skip < neighbour.text.len() && neighbour.text.is_char_boundary(skip) {
+                    context.push_str(&neighbour.text[skip..]);
+                }
+            } else {
+                if context.len() > 0 {
+                    context.push_str(" ");
+                }
+                context.push_str(&neighbour.text);
+            }
+            context_end = context_end.max(neighbour.end);
+        }
+
+        Some(Hit {
+            filename: entry.filename.clone(),
+            id,
+            score,
+            chunk,
+            context,
+        })
+    }
+
 }
 
 impl Retriever for RetrieverImpl {
@@ -62,45 +196,51 @@ impl Retriever for RetrieverImpl {
             retriever_channel: channel(),
             indexer_channel,
             catalog,
+            parsers: Registry::from_settings(),
         }
     }
 
-    fn retrieve(&self, query: String) -> Vec<(String, String)> {
-        let mut results: Vec<(String, String)> = Vec::new();
+    fn retrieve(&self, query: String, options: &QueryOptions) -> Result<Vec<Hit>, String> {
+        let mut results: Vec<Hit> = Vec::new();
+        let top_k = options.search.top_k;
+        let mut options = options.clone();
+        if options.path_prefix.is_some() {
+            options.search.top_k *= FILTER_OVERFETCH;
+        }
 
-        let mut files_set = HashSet::new();
-        let ch = channel();
-        self.indexer_channel.send(indexer::Message::RetrieveDocument(query, ch.0)).unwrap();
-        if let Ok(rep) = ch.1.recv() {
-            match rep {
-                indexer::Reply::Docs(ids_scores) => {
-                    for (id,score) in ids_scores {
-                        log::debug!("Retriever: id: {}, score: {}", id, score);
-                        let rep = self.catalog.get_by_index(id);
-                        log::debug!("Retriever: rep: {:?}", rep);
-                        if let Ok(catalog::Entry { filename, .. }) = rep {
-                            files_set.insert(filename);
-                        }
-                    }
-                }
-                _ => {}
-            }
+        let mut rankings = Vec::new();
+        if options.mode != SearchMode::Lexical {
+            rankings.push(self.retrieve_semantic(query.clone(), &options.search)?);
+        }
+        if options.mode != SearchMode::Semantic {
+            let lexical = self.catalog.search_lexical(&query, options.search.top_k);
+            log::debug!("Retriever: lexical hits: {:?}", lexical);
+            rankings.push(lexical);
         }
+        let ids_scores = match options.mode {
+            SearchMode::Hybrid => reciprocal_rank_fusion(&rankings, options.search.top_k),
+            _ => rankings.pop().unwrap(),
+        };
 
-        for file in files_set {
-            let catalog = self.catalog.clone();
-            log::debug!("Retriever: file: {:?}", file);
-            if !catalog.is_file_in_catalog(file.to_string()) {
-                continue;
+        for (id,score) in ids_scores {
+            if results.len() >= top_k {
+                break;
             }
-            let content: Result<String, Box<dyn StdError>> = Parser::new().parse(&file);
-            if let Ok(content) = content {
-                results.push((file, content));
-            } else {
-                log::debug!("Retriever: error: {:?}", content);
+            log::debug!("Retriever: id: {}, score: {}", id, score);
+            let rep = self.catalog.get_by_index(id);
+            log::debug!("Retriever: rep: {:?}", rep);
+            if let Ok(entry) = rep {
+                if let Some(prefix) = &options.path_prefix {
+                    if !catalog::path_contains(prefix, &entry.filename) {
+                        continue;
+                    }
+                }
+                if let Some(hit) = self.make_hit(&entry, id, score) {
+                    results.push(hit);
+                }
             }
         }
-        results
+        Ok(results)
     }
 
     fn run(&mut self) {
@@ -108,10 +248,15 @@ impl Retriever for RetrieverImpl {
             let query = self.retriever_channel.1.recv().unwrap();
             log::debug!("Retriever received query: {:?}", query);
             match query {
-                Message::RetrieveByQuery(query, sender) => {
-                    let results = self.retrieve(query);
-                    log::debug!("Retriever retrieved results: {}", results.len());
-                    sender.send(Reply::Docs(results)).unwrap();
+                Message::RetrieveByQuery(query, options, sender) => {
+                    let reply = match self.retrieve(query, &options) {
+                        Ok(results) => {
+                            log::debug!("Retriever retrieved results: {}", results.len());
+                            Reply::Docs(results)
+                        }
+                        Err(e) => Reply::Failed(e),
+                    };
+                    let _ = sender.send(reply);
                 }
                 Message::RetrieveByPath(path, sender) => {
                     let results = self.retrieve_by_path(path);
@@ -125,11 +270,16 @@ impl Retriever for RetrieverImpl {
         }
     }
 
+    // Only catalogued files are returned, so this can't be used to read
+    // arbitrary files through the daemon
     fn retrieve_by_path(&self, path: String) -> Vec<String> {
         let mut results = Vec::new();
-        let content = Parser::new().parse(&path);
+        if !self.catalog.is_file_in_catalog(path.clone()) {
+            return results;
+        }
+        let content = self.parsers.parse(&path);
         if content.is_ok() {
-            results.push(content.unwrap());
+            results.push(content.unwrap().text());
         }
         results
     }
@@ -138,11 +288,32 @@ impl Retriever for RetrieverImpl {
         let mut results = Vec::new();
         let rep = self.catalog.get_by_index(id);
         if let Ok(catalog::Entry { filename, .. }) = rep {
-            let content = Parser::new().parse(&filename);
+            let content = self.parsers.parse(&filename);
             if content.is_ok() {
-                results.push(content.unwrap());
+                results.push(content.unwrap().text());
             }
         }
         results
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuses_rankings_by_rank() {
+        let semantic = vec![(1, 0.9), (2, 0.8), (3, 0.7)];
+        let lexical = vec![(3, 12.0), (4, 3.0)];
+        let fused = reciprocal_rank_fusion(&vec![semantic, lexical], 4);
+        // 3 is in both rankings, 1 tops one of them
+        assert_eq!(fused.iter().map(|(id, _)| *id).take(2).collect::<Vec<u64>>(), vec![3, 1]);
+        assert!((fused[0].1 - (1.0 / 63.0 + 1.0 / 61.0)).abs() < 1e-6);
+        let mut ids = fused.iter().map(|(id, _)| *id).collect::<Vec<u64>>();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+        let top = reciprocal_rank_fusion(&vec![vec![(1, 0.9), (2, 0.8)], vec![(2, 1.0)]], 1);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].0, 2);
+    }
+}
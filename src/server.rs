@@ -0,0 +1,563 @@
+// Copyright: (c) 2023 Sureshkumar T
+// License: Apache-2.0
+
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::io::BufReader;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixStream, UnixListener};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Sender, Receiver, RecvTimeoutError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::catalog;
+use crate::catalog::Catalog;
+use crate::crawler;
+use crate::error::Error;
+use crate::indexer;
+use crate::protocol;
+use crate::query_processor;
+use crate::retriever;
+use crate::settings;
+
+// How often a worker waiting on answers checks whether its client is still there
+const DISCONNECT_POLL: Duration = Duration::from_millis(200);
+
+// Accepts connections on the unix socket and hands them to a pool of workers.
+// Connections beyond what the workers and the queue can take are turned away
+// with an error instead of piling up.
+pub struct Server {
+    listener: UnixListener,
+    worker: Worker,
+}
+
+// Handles requests, whichever way they came in.  Each connection handling
+// thread has its own clone.
+#[derive(Clone)]
+pub struct Worker {
+    catalog: Arc<Catalog>,
+    crawler: Sender<crawler::Message>,
+    // paths and requests waiting for the crawler
+    crawler_queue: Arc<AtomicUsize>,
+    indexer: Sender<indexer::Message>,
+    retriever: Sender<retriever::Message>,
+    qa: Sender<query_processor::Message>,
+}
+
+// Where the responses to a request go
+pub trait Client {
+    fn send(&mut self, response: &protocol::Response) -> Result<(), Box<dyn StdError>>;
+    // sends the error a request failed with, which ends its responses
+    fn fail(&mut self, response: &protocol::Response) -> Result<(), Box<dyn StdError>> {
+        self.send(response)
+    }
+    // true if the client went away and the request can be abandoned
+    fn gone(&mut self) -> bool;
+}
+
+struct SocketClient<'a> {
+    stream: &'a UnixStream,
+    reader: BufReader<&'a UnixStream>,
+}
+
+impl<'a> Client for SocketClient<'a> {
+    fn send(&mut self, response: &protocol::Response) -> Result<(), Box<dyn StdError>> {
+        protocol::write_message(&mut self.stream, response)
+    }
+
+    fn gone(&mut self) -> bool {
+        client_gone(self.stream)
+    }
+}
+
+fn passage(hit: &retriever::Hit) -> protocol::Passage {
+    protocol::Passage {
+        file: hit.filename.clone(),
+        chunk_id: hit.id,
+        score: hit.score,
+        chunk_text: hit.chunk.text.clone(),
+        start: hit.chunk.start,
+        end: hit.chunk.end,
+        char_start: hit.chunk.char_start,
+        char_end: hit.chunk.char_end,
+        page: hit.chunk.page,
+        section: hit.chunk.section.clone(),
+    }
+}
+
+// Listings are sent in batches of this many files
+const LIST_BATCH: usize = 200;
+
+fn file_info(entry: &catalog::Entry) -> protocol::FileInfo {
+    let indexed_at = match entry.indexed_at {
+        0 => None,
+        secs => chrono::NaiveDateTime::from_timestamp_opt(secs as i64, 0)
+            .map(|t| chrono::DateTime::<chrono::Utc>::from_utc(t, chrono::Utc).to_rfc3339()),
+    };
+    protocol::FileInfo {
+        path: entry.filename.clone(),
+        status: String::from("indexed"),
+        detail: None,
+        vector_ids: entry.indexes.clone(),
+        chunks: entry.indexes.len(),
+        indexed_at,
+        size: entry.size,
+        mime: entry.mime.clone(),
+        hash: entry.hash.clone(),
+        metadata: entry.metadata.clone(),
+    }
+}
+
+// Splits the reason a file was skipped, as stored in the catalog, into its
+// code and what went wrong
+fn skip_reason(reason: &str) -> (&str, Option<&str>) {
+    match reason.split_once(": ") {
+        Some((code, detail)) => (code, Some(detail)),
+        None => (reason, None),
+    }
+}
+
+fn skipped_info(filename: &str, reason: &str) -> protocol::FileInfo {
+    let size = std::fs::metadata(filename).map(|m| m.len()).unwrap_or(0);
+    let (code, detail) = skip_reason(reason);
+    protocol::FileInfo {
+        path: filename.to_string(),
+        status: code.to_string(),
+        detail: detail.map(|d| d.to_string()),
+        vector_ids: Vec::new(),
+        chunks: 0,
+        indexed_at: None,
+        size,
+        mime: mime_guess::from_path(filename).first_or_octet_stream().to_string(),
+        hash: String::new(),
+        metadata: BTreeMap::new(),
+    }
+}
+
+// Size in bytes of a file, or of everything below a directory
+fn disk_usage(path: &Path) -> u64 {
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return 0,
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    match std::fs::read_dir(path) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| disk_usage(&e.path())).sum(),
+        Err(_) => 0,
+    }
+}
+
+// Applies the per query overrides of a request to the configured defaults
+fn query_options(options: &protocol::QueryOptions) -> Result<retriever::QueryOptions, Error> {
+    let mut result = retriever::QueryOptions::default();
+    if let Some(mode) = &options.mode {
+        result.mode = match retriever::SearchMode::from_str(mode) {
+            Some(mode) => mode,
+            None => return Err(Error::new(&format!("unknown search mode {}", mode))),
+        };
+    }
+    if let Some(top_k) = options.top_k {
+        if top_k == 0 || top_k > indexer::MAX_TOP_K {
+            return Err(Error::new(&format!("top_k must be between 1 and {}", indexer::MAX_TOP_K)));
+        }
+        result.search.top_k = top_k;
+    }
+    if let Some(min_score) = options.min_score {
+        if !min_score.is_finite() {
+            return Err(Error::new("min_score must be a finite number"));
+        }
+        result.search.min_score = min_score;
+    }
+    if options.nprobe.is_some() {
+        result.search.nprobe = options.nprobe;
+    }
+    if options.ef_search.is_some() {
+        result.search.ef_search = options.ef_search;
+    }
+    result.path_prefix = options.filters.path_prefix.clone();
+    Ok(result)
+}
+
+// True if the client closed the connection.  A client that only shut down
+// its sending side still gets its responses, so the end of input doesn't
+// count; poll reports a hangup once neither side can write.
+fn client_gone(stream: &UnixStream) -> bool {
+    let mut fds = libc::pollfd { fd: stream.as_raw_fd(), events: 0, revents: 0 };
+    // POLLHUP and POLLERR are reported without asking for any events
+    let ready = unsafe { libc::poll(&mut fds, 1, 0) };
+    ready < 0 || fds.revents & (libc::POLLHUP | libc::POLLERR) != 0
+}
+
+impl Server {
+    pub fn new(worker: Worker) -> Self {
+        let sock_path = settings::get_socket_path();
+        let listener = UnixListener::bind(sock_path).unwrap();
+        Server {
+            listener,
+            worker,
+        }
+    }
+
+    pub fn run(&mut self) {
+        let conf = settings::get_config(None);
+        let (tx, rx): (_, Receiver<UnixStream>) = sync_channel(conf.server_queue_size);
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..conf.server_workers {
+            let rx = rx.clone();
+            let mut worker = self.worker.clone();
+            thread::spawn(move || loop {
+                let stream = rx.lock().unwrap().recv();
+                match stream {
+                    Ok(stream) => handle_connection(&mut worker, stream),
+                    Err(_) => return,
+                }
+            });
+        }
+
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => match tx.try_send(stream) {
+                    Ok(_) => {}
+                    Err(TrySendError::Full(stream)) => {
+                        log::warn!("Too many connections, rejecting client");
+                        let busy = protocol::Response::error("", "server busy, try again later");
+                        let _ = protocol::write_message(&mut &stream, &busy);
+                    }
+                    Err(TrySendError::Disconnected(_)) => return,
+                },
+                Err(e) => log::error!("Error accepting socket: {}", e),
+            }
+        }
+    }
+}
+
+// Ends the responses to a request that didn't fail
+fn done(client: &mut dyn Client, id: &str, count: usize) -> Result<(), Box<dyn StdError>> {
+    client.send(&protocol::Response::new(id, protocol::ResponseBody::Done { count }))
+}
+
+// True if a read gave up because of the socket's read timeout
+fn is_timeout(e: &(dyn StdError + 'static)) -> bool {
+    match e.downcast_ref::<std::io::Error>() {
+        Some(e) => e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut,
+        None => false,
+    }
+}
+
+// Serves the requests on a connection, one after the other, until the client
+// closes it
+fn handle_connection(worker: &mut Worker, stream: UnixStream) {
+    // accepted sockets inherit non-blocking mode from the listener on macOS
+    let timeout = Some(Duration::from_secs(settings::get_config(None).request_timeout_secs));
+    if let Err(e) = stream.set_nonblocking(false)
+        .and_then(|_| stream.set_read_timeout(timeout))
+        .and_then(|_| stream.set_write_timeout(timeout)) {
+        log::error!("Error setting up socket: {}", e);
+        return;
+    }
+    let mut client = SocketClient { stream: &stream, reader: BufReader::new(&stream) };
+    let idle = Some(Duration::from_secs(settings::get_config(None).idle_timeout_secs));
+    loop {
+        let line = match protocol::read_line(&mut client.reader) {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            // an idle client is dropped without a response; it would be
+            // read as the answer to the client's next request
+            Err(e) if is_timeout(e.as_ref()) => {
+                log::debug!("Closing idle connection");
+                break;
+            }
+            Err(e) => {
+                log::debug!("Error reading from socket: {}", e);
+                let _ = client.send(&protocol::Response::error("", &e.to_string()));
+                break;
+            }
+        };
+        let handled = match protocol::parse_request(&line) {
+            Ok(request) => worker.handle(request, &mut client),
+            Err(e) => {
+                // echo the id back if the request got that far
+                let id = serde_json::from_str::<serde_json::Value>(&line).ok()
+                    .and_then(|v| v["id"].as_str().map(|id| id.to_string()))
+                    .unwrap_or_default();
+                client.send(&protocol::Response::error(&id, &format!("invalid request: {}", e)))
+            }
+        };
+        if let Err(e) = handled {
+            log::debug!("Request cancelled: {}", e);
+            return;
+        }
+        // interactive clients keep the connection open between requests
+        if let Err(e) = stream.set_read_timeout(idle) {
+            log::error!("Error setting up socket: {}", e);
+            break;
+        }
+    }
+    if let Err(e) = stream.shutdown(std::net::Shutdown::Both) {
+        log::debug!("Error shutting down socket: {}", e);
+    }
+}
+
+impl Worker {
+    pub fn new(catalog: Arc<Catalog>, crawler: Sender<crawler::Message>, crawler_queue: Arc<AtomicUsize>, indexer: Sender<indexer::Message>, retriever: Sender<retriever::Message>, qa: Sender<query_processor::Message>) -> Self {
+        Worker { catalog, crawler, crawler_queue, indexer, retriever, qa }
+    }
+
+    // Sends the responses to the request.  Fails if the client went away.
+    pub fn handle(&mut self, request: protocol::Request, client: &mut dyn Client) -> Result<(), Box<dyn StdError>> {
+        match request.body {
+            protocol::RequestBody::Query { query, options } => {
+                log::debug!("Server received query: {}", query);
+                let options = match query_options(&options) {
+                    Ok(options) => options,
+                    Err(e) => return client.send(&protocol::Response::error(&request.id, &e.to_string())),
+                };
+                let hits = match self.retrieve(&query, options) {
+                    Ok(hits) => hits,
+                    Err(e) => return client.fail(&protocol::Response::error(&request.id, &e)),
+                };
+                let count = self.search(&request.id, &query, hits, client)?;
+                done(client, &request.id, count)
+            }
+            protocol::RequestBody::Document { chunk_id, path } => {
+                let (sender, receiver) = channel();
+                let message = match (chunk_id, path) {
+                    (Some(id), _) => retriever::Message::RetrieveById(id, sender),
+                    (None, Some(path)) => retriever::Message::RetrieveByPath(path, sender),
+                    (None, None) => return client.send(&protocol::Response::error(&request.id, "either chunk_id or path is required")),
+                };
+                self.retriever.send(message).unwrap();
+                let contents = receiver.recv().unwrap_or_default();
+                if contents.len() == 0 {
+                    return client.send(&protocol::Response::error(&request.id, "document not found"));
+                }
+                client.send(&protocol::Response::new(&request.id, protocol::ResponseBody::Document { contents }))?;
+                done(client, &request.id, 1)
+            }
+            protocol::RequestBody::Status => {
+                let status = self.status();
+                client.send(&protocol::Response::new(&request.id, protocol::ResponseBody::Status { status }))?;
+                done(client, &request.id, 1)
+            }
+            protocol::RequestBody::List { prefix } => {
+                let prefix = prefix.unwrap_or_default();
+                let mut files = self.catalog.entries_below(&prefix).iter().map(file_info).collect::<Vec<_>>();
+                files.extend(self.catalog.skipped_below(&prefix).iter().map(|(f, reason)| skipped_info(f, reason)));
+                files.sort_by(|a, b| a.path.cmp(&b.path));
+                let count = files.len();
+                for batch in files.chunks(LIST_BATCH) {
+                    client.send(&protocol::Response::new(&request.id, protocol::ResponseBody::Files { files: batch.to_vec() }))?;
+                }
+                done(client, &request.id, count)
+            }
+            protocol::RequestBody::Show { path } => {
+                let entry = match self.catalog.get_by_file(path.clone()) {
+                    Ok(entry) => entry,
+                    Err(_) => {
+                        return match self.catalog.get_skipped(&path) {
+                            Some(reason) => {
+                                let file = skipped_info(&path, &reason);
+                                client.send(&protocol::Response::new(&request.id, protocol::ResponseBody::File { file, chunks: Vec::new() }))?;
+                                done(client, &request.id, 1)
+                            }
+                            None => client.send(&protocol::Response::error(&request.id, &format!("{} is not in the catalog", path))),
+                        };
+                    }
+                };
+                let chunks = entry.indexes.iter()
+                    .filter_map(|id| self.catalog.get_chunk(*id).ok().map(|c| protocol::ChunkInfo { id: *id, text: c.text, start: c.start, end: c.end, page: c.page, section: c.section }))
+                    .collect();
+                client.send(&protocol::Response::new(&request.id, protocol::ResponseBody::File { file: file_info(&entry), chunks }))?;
+                done(client, &request.id, 1)
+            }
+            protocol::RequestBody::Add { path } => {
+                let (sender, receiver) = channel();
+                self.admin(&request.id, Some(&path), crawler::Message::Add(path.clone(), sender), receiver, client)
+            }
+            protocol::RequestBody::Remove { path } => {
+                let (sender, receiver) = channel();
+                self.admin(&request.id, Some(&path), crawler::Message::Remove(path.clone(), sender), receiver, client)
+            }
+            protocol::RequestBody::Reindex { path } => {
+                let (sender, receiver) = channel();
+                self.admin(&request.id, path.as_deref(), crawler::Message::Reindex(path.clone(), sender), receiver, client)
+            }
+        }
+    }
+
+    // Has the crawler carry out an admin request and relays its progress
+    fn admin(&mut self, id: &str, path: Option<&str>, message: crawler::Message, receiver: Receiver<crawler::Reply>, client: &mut dyn Client) -> Result<(), Box<dyn StdError>> {
+        if let Some(path) = path {
+            if !Path::new(path).is_absolute() {
+                return client.send(&protocol::Response::error(id, &format!("path {} is not absolute", path)));
+            }
+        }
+        self.crawler_queue.fetch_add(1, Ordering::Relaxed);
+        if self.crawler.send(message).is_err() {
+            self.crawler_queue.fetch_sub(1, Ordering::Relaxed);
+            return client.send(&protocol::Response::error(id, "crawler is not running"));
+        }
+        while let Ok(reply) = receiver.recv() {
+            match reply {
+                crawler::Reply::Progress(file, outcome) => {
+                    let (action, detail) = match outcome {
+                        crawler::Outcome::Indexed(chunks) => ("indexed", Some(format!("{} chunks", chunks))),
+                        crawler::Outcome::Unchanged => ("unchanged", None),
+                        crawler::Outcome::Moved(from) => ("moved", Some(from)),
+                        crawler::Outcome::Removed => ("removed", None),
+                        crawler::Outcome::Skipped(reason) => ("skipped", Some(reason)),
+                        crawler::Outcome::Failed(error) => ("failed", Some(error)),
+                    };
+                    client.send(&protocol::Response::new(id, protocol::ResponseBody::Progress { file, action: action.to_string(), detail }))?;
+                }
+                crawler::Reply::Summary(s) => {
+                    let summary = protocol::ResponseBody::Summary { indexed: s.indexed, unchanged: s.unchanged, removed: s.removed, skipped: s.skipped, failed: s.failed };
+                    client.send(&protocol::Response::new(id, summary))?;
+                    return done(client, id, s.indexed + s.unchanged + s.removed + s.skipped + s.failed);
+                }
+            }
+        }
+        client.send(&protocol::Response::error(id, "crawler did not respond"))
+    }
+
+    fn status(&self) -> protocol::StatusReport {
+        let (sender, receiver) = channel();
+        self.indexer.send(indexer::Message::Status(sender)).unwrap();
+        let index = match receiver.recv() {
+            Ok(indexer::Reply::Status(status)) => Some(status),
+            _ => None,
+        };
+        let mut skipped = BTreeMap::new();
+        for (_, reason) in self.catalog.skipped() {
+            *skipped.entry(skip_reason(&reason).0.to_string()).or_insert(0) += 1;
+        }
+        let last_scan = std::fs::read_to_string(settings::get_scan_status_file()).ok().map(|s| s.trim().to_string());
+        protocol::StatusReport {
+            files: self.catalog.file_count(),
+            vectors: index.as_ref().map(|i| i.ntotal).unwrap_or(0),
+            index_factory: index.as_ref().map(|i| i.factory.clone()).unwrap_or_default(),
+            last_scan,
+            queue_depth: self.crawler_queue.load(Ordering::Relaxed) + index.as_ref().map(|i| i.queue_depth).unwrap_or(0),
+            skipped,
+            embedding_model: index.as_ref().map(|i| i.model_id.clone()).unwrap_or_default(),
+            qa_model: query_processor::qa_model_id(),
+            db_bytes: disk_usage(Path::new(&settings::get_db_dir())),
+            index_bytes: disk_usage(Path::new(&settings::get_index_location())),
+        }
+    }
+
+    // Passages for the query, or why they couldn't be retrieved
+    fn retrieve(&mut self, query: &str, options: retriever::QueryOptions) -> Result<Vec<retriever::Hit>, String> {
+        let (sender, receiver) = channel();
+        if self.retriever.send(retriever::Message::RetrieveByQuery(query.to_string(), options, sender)).is_err() {
+            return Err(String::from("retriever is not running"));
+        }
+        match receiver.recv() {
+            Ok(retriever::Reply::Docs(hits)) => Ok(hits),
+            Ok(retriever::Reply::Failed(e)) => Err(e),
+            Err(_) => Err(String::from("retriever did not respond")),
+        }
+    }
+
+    // Sends the retrieved passages to the client, then has the question
+    // answering model extract answers from each and sends those as they come
+    // in.  Stops and cancels the outstanding answers if the client disconnects
+    // in the meantime.  Returns the number of answers.
+    fn search(&mut self, id: &str, query: &str, hits: Vec<retriever::Hit>, client: &mut dyn Client) -> Result<usize, Box<dyn StdError>> {
+        let passages = hits.iter().map(passage).collect();
+        client.send(&protocol::Response::new(id, protocol::ResponseBody::Hits { hits: passages }))?;
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = channel();
+        for hit in hits.iter() {
+            self.qa.send(query_processor::Message::Answer(query.to_string(), hit.clone(), cancel.clone(), sender.clone())).unwrap();
+        }
+
+        let mut count = 0;
+        let mut pending = hits.len();
+        while pending > 0 {
+            match receiver.recv_timeout(DISCONNECT_POLL) {
+                Ok(answers) => {
+                    pending -= 1;
+                    if answers.len() == 0 {
+                        continue;
+                    }
+                    count += answers.len();
+                    let results = answers.iter().map(|r| r.to_result()).collect();
+                    let sent = client.send(&protocol::Response::new(id, protocol::ResponseBody::Results { results }));
+                    if sent.is_err() {
+                        cancel.store(true, Ordering::Relaxed);
+                        return sent.map(|_| 0);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if client.gone() {
+                        cancel.store(true, Ordering::Relaxed);
+                        return Err(Box::new(Error::new("client disconnected")));
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    use super::*;
+
+    // Keeps what was sent to it
+    struct Recorder {
+        responses: Vec<protocol::Response>,
+    }
+
+    impl Client for Recorder {
+        fn send(&mut self, response: &protocol::Response) -> Result<(), Box<dyn StdError>> {
+            self.responses.push(response.clone());
+            Ok(())
+        }
+
+        fn gone(&mut self) -> bool {
+            false
+        }
+    }
+
+    // A worker whose retriever fails every search with `error`
+    fn failing_worker(error: &str) -> Worker {
+        settings::init_for_tests();
+        let (retriever, requests) = channel();
+        let error = error.to_string();
+        thread::spawn(move || {
+            while let Ok(retriever::Message::RetrieveByQuery(_, _, tx)) = requests.recv() {
+                let _ = tx.send(retriever::Reply::Failed(error.clone()));
+            }
+        });
+        Worker::new(Arc::new(Catalog::temporary()), channel().0, Arc::new(AtomicUsize::new(0)), channel().0, retriever, channel().0)
+    }
+
+    #[test]
+    fn failed_search_is_sent_as_error() {
+        let mut worker = failing_worker("search failed: index is corrupt");
+        let mut client = Recorder { responses: Vec::new() };
+        let request = protocol::Request::new(String::from("q1"), protocol::RequestBody::Query { query: String::from("where is it"), options: protocol::QueryOptions::default() });
+        worker.handle(request, &mut client).unwrap();
+
+        assert_eq!(client.responses.len(), 1);
+        assert_eq!(client.responses[0].id, "q1");
+        match &client.responses[0].body {
+            protocol::ResponseBody::Error { message } => assert_eq!(message, "search failed: index is corrupt"),
+            body => panic!("expected an error, got {:?}", body),
+        }
+    }
+}
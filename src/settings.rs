@@ -4,6 +4,7 @@
 use std::error::Error;
 use dirs;
 use lazy_static::lazy_static;
+use std::collections::HashMap;
 use std::path::PathBuf;
 use config::{Config, File, Environment};
 
@@ -14,6 +15,40 @@ pub struct Settings {
     pub db_dir: String,
     pub scan_status_file: String,
     pub index_location: String,
+    pub watch: bool,
+    pub watch_debounce_ms: u64,
+    pub chunk_tokens: usize,
+    pub chunk_overlap_tokens: usize,
+    pub embed_batch_size: usize,
+    pub embed_memory_mb: usize,
+    pub embedder: String,
+    pub embedding_path: Option<String>,
+    pub qa_path: Option<String>,
+    pub splitter_path: Option<String>,
+    pub context_chunks: usize,
+    pub search_mode: String,
+    pub index_factory: String,
+    pub train_min_vectors: u64,
+    pub train_max_vectors: usize,
+    pub retrain_growth: f64,
+    pub nprobe: Option<u32>,
+    pub ef_search: Option<u32>,
+    pub top_k: usize,
+    pub min_score: f32,
+    pub server_workers: usize,
+    pub server_queue_size: usize,
+    pub request_timeout_secs: u64,
+    pub idle_timeout_secs: u64,
+    pub http_enabled: bool,
+    pub http_port: u16,
+    pub http_workers: usize,
+    pub editor: Option<String>,
+    pub pdf_viewer: Option<String>,
+    pub pdf_fallback: String,
+    pub parsers_disabled: Vec<String>,
+    pub parser_extensions: HashMap<String, String>,
+    pub parser_mime_types: HashMap<String, String>,
+    pub max_part_mb: u64,
 }
 
 pub struct LocalModeSettings {
@@ -42,6 +77,40 @@ impl Default for Settings {
             db_dir: String::from("~/.local/share/semdesk/db"),
             scan_status_file: String::from("~/.local/share/semdesk/scan_status.txt"),
             index_location: String::from("~/.cache/semdesk/.index"),
+            watch: true,
+            watch_debounce_ms: 2000,
+            chunk_tokens: 128,
+            chunk_overlap_tokens: 32,
+            embed_batch_size: 32,
+            embed_memory_mb: 512,
+            embedder: String::from("bert"),
+            embedding_path: None,
+            qa_path: None,
+            splitter_path: None,
+            context_chunks: 1,
+            search_mode: String::from("hybrid"),
+            index_factory: String::from("IDMap,Flat"),
+            train_min_vectors: 10000,
+            train_max_vectors: 100000,
+            retrain_growth: 2.0,
+            nprobe: None,
+            ef_search: None,
+            top_k: 6,
+            min_score: 0.10,
+            server_workers: 4,
+            server_queue_size: 16,
+            request_timeout_secs: 30,
+            idle_timeout_secs: 15,
+            http_enabled: false,
+            http_port: 7373,
+            http_workers: 2,
+            editor: None,
+            pdf_viewer: None,
+            pdf_fallback: String::from("ghostscript"),
+            parsers_disabled: vec![],
+            parser_extensions: HashMap::new(),
+            parser_mime_types: HashMap::new(),
+            max_part_mb: 64,
         }
     }
 }
@@ -53,15 +122,9 @@ pub fn get_config_dir() -> String {
 }
 
 pub fn get_db_dir() -> String {
-    let dir = get_config(None).db_dir.clone();
-    if dir.starts_with("~") {
-        let home = dirs::home_dir().unwrap();
-        let db_dir = home.join(&dir[2..]);
-        log::debug!("DB dir: {}", db_dir.to_str().unwrap());
-        db_dir.to_str().unwrap().to_string()
-    } else {
-        dir
-    }
+    let db_dir = expand_home(&get_config(None).db_dir);
+    log::debug!("DB dir: {}", db_dir);
+    db_dir
 }
 
 impl Settings {
@@ -76,7 +139,41 @@ impl Settings {
             let db_dir: String = config.get("db.dir").unwrap_or(String::from("~/.local/share/semdesk/db"));
             let scan_status_file = config.get("crawler.scan_status_file").unwrap_or("~/.local/share/semdesk/scan_status.txt");
             let index_location = config.get("index.location").unwrap_or("~/.cache/semdesk/.index");
-            Ok(Settings { files, max_scan_depth, db_dir, scan_status_file: scan_status_file.to_string(), index_location: index_location.to_string() })
+            let watch: bool = config.get("crawler.watch").unwrap_or(true);
+            let watch_debounce_ms: u64 = config.get("crawler.watch_debounce_ms").unwrap_or(2000);
+            let chunk_tokens: usize = config.get("index.chunk_tokens").unwrap_or(128);
+            let chunk_overlap_tokens: usize = config.get("index.chunk_overlap_tokens").unwrap_or(32);
+            let embed_batch_size: usize = config.get("index.embed_batch_size").unwrap_or(32);
+            let embed_memory_mb: usize = config.get("index.embed_memory_mb").unwrap_or(512);
+            let embedder: String = config.get("model.embedder").unwrap_or(String::from("bert"));
+            let embedding_path: Option<String> = config.get("model.embedding_path").ok();
+            let qa_path: Option<String> = config.get("model.qa_path").ok();
+            let splitter_path: Option<String> = config.get("model.splitter_path").ok();
+            let context_chunks: usize = config.get("retriever.context_chunks").unwrap_or(1);
+            let search_mode: String = config.get("retriever.mode").unwrap_or(String::from("hybrid"));
+            let index_factory: String = config.get("index.factory").unwrap_or(String::from("IDMap,Flat"));
+            let train_min_vectors: u64 = config.get("index.train_min_vectors").unwrap_or(10000);
+            let train_max_vectors: usize = config.get("index.train_max_vectors").unwrap_or(100000);
+            let retrain_growth: f64 = config.get("index.retrain_growth").unwrap_or(2.0);
+            let nprobe: Option<u32> = config.get("index.nprobe").ok();
+            let ef_search: Option<u32> = config.get("index.ef_search").ok();
+            let top_k: usize = config.get("retriever.top_k").unwrap_or(6);
+            let min_score: f32 = config.get("retriever.min_score").unwrap_or(0.10);
+            let server_workers: usize = config.get("server.workers").unwrap_or(4);
+            let server_queue_size: usize = config.get("server.queue_size").unwrap_or(16);
+            let request_timeout_secs: u64 = config.get("server.request_timeout_secs").unwrap_or(30);
+            let idle_timeout_secs: u64 = config.get("server.idle_timeout_secs").unwrap_or(15);
+            let http_enabled: bool = config.get("http.enabled").unwrap_or(false);
+            let http_port: u16 = config.get("http.port").unwrap_or(7373);
+            let http_workers: usize = config.get("http.workers").unwrap_or(2);
+            let editor: Option<String> = config.get("open.editor").ok();
+            let pdf_viewer: Option<String> = config.get("open.pdf_viewer").ok();
+            let pdf_fallback: String = config.get("parser.pdf_fallback").unwrap_or(String::from("ghostscript"));
+            let parsers_disabled: Vec<String> = config.get("parser.disabled").unwrap_or(vec![]);
+            let parser_extensions: HashMap<String, String> = config.get("parser.extensions").unwrap_or_default();
+            let parser_mime_types: HashMap<String, String> = config.get("parser.mime_types").unwrap_or_default();
+            let max_part_mb: u64 = config.get("parser.max_part_mb").unwrap_or(64);
+            Ok(Settings { files, max_scan_depth, db_dir, scan_status_file: scan_status_file.to_string(), index_location: index_location.to_string(), watch, watch_debounce_ms, chunk_tokens, chunk_overlap_tokens, embed_batch_size, embed_memory_mb, embedder, embedding_path, qa_path, splitter_path, context_chunks, search_mode, index_factory, train_min_vectors, train_max_vectors, retrain_growth, nprobe, ef_search, top_k, min_score, server_workers, server_queue_size, request_timeout_secs, idle_timeout_secs, http_enabled, http_port, http_workers, editor, pdf_viewer, pdf_fallback, parsers_disabled, parser_extensions, parser_mime_types, max_part_mb })
         } else {
             Ok(Settings::default())
         }
@@ -98,6 +195,7 @@ pub fn get_config(local_mode: Option<LocalModeSettings>) -> &'static Settings {
                     db_dir: PathBuf::from(local_mode.db_dir.clone()).join(".semdesk_db").to_str().unwrap().to_string(),
                     scan_status_file: PathBuf::from(local_mode.db_dir.clone()).join(".semdesk_scan_status.txt").to_str().unwrap().to_string(),
                     index_location: PathBuf::from(local_mode.db_dir.clone()).join(".semdesk_index").to_str().unwrap().to_string(),
+                    ..Settings::default()
                 });
             } else {
                 CONFIG = Some(Settings::new().unwrap());
@@ -107,16 +205,20 @@ pub fn get_config(local_mode: Option<LocalModeSettings>) -> &'static Settings {
     }
 }
 
+// Points the config at a scratch directory for the tests of this process, so
+// they never touch the user's db or index.  Tests that read settings call it
+// first, as the config can only be set once.
+#[cfg(test)]
+pub fn init_for_tests() -> &'static Settings {
+    let dir = std::env::temp_dir().join(format!("semdesk-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    get_config(Some(LocalModeSettings::new(dir.to_str().unwrap().to_string(), true, false, 2)))
+}
+
 pub fn get_scan_status_file() -> String {
-    let file = get_config(None).scan_status_file.clone();
-    if file.starts_with("~") {
-        let home = dirs::home_dir().unwrap();
-        let scan_status_file = home.join(&file[2..]);
-        log::debug!("Scan status file: {}", scan_status_file.to_str().unwrap());
-        scan_status_file.to_str().unwrap().to_string()
-    } else {
-        file.to_string()
-    }
+    let scan_status_file = expand_home(&get_config(None).scan_status_file);
+    log::debug!("Scan status file: {}", scan_status_file);
+    scan_status_file
 }
 
 pub fn get_socket_path() -> String {
@@ -125,7 +227,69 @@ pub fn get_socket_path() -> String {
     sock_path.to_str().unwrap().to_string()
 }
 
+// Lines entered in `semdesk-cli shell`
+pub fn get_shell_history_file() -> String {
+    let userdir = dirs::home_dir().unwrap();
+    let history = userdir.join(".local/share/semdesk_history");
+    history.to_str().unwrap().to_string()
+}
+
+// Results of the last query, for `semdesk-cli open`
+pub fn get_last_results_file() -> String {
+    let userdir = dirs::home_dir().unwrap();
+    let results = userdir.join(".local/share/semdesk_last_results.json");
+    results.to_str().unwrap().to_string()
+}
+
+// Bearer token for the http api
+pub fn get_http_token_file() -> String {
+    PathBuf::from(get_config_dir()).join("http_token").to_str().unwrap().to_string()
+}
+
 pub fn get_index_location() -> String {
     let conf = get_config(None);
     return conf.index_location.clone();
 }
+
+// Replaces a leading ~ or ~/ by the home directory.  Other paths, including
+// ~user ones, are left as they are.
+pub fn expand_home(path: &str) -> String {
+    let home = || dirs::home_dir().unwrap();
+    if path == "~" {
+        home().to_str().unwrap().to_string()
+    } else if let Some(rest) = path.strip_prefix("~/") {
+        home().join(rest).to_str().unwrap().to_string()
+    } else {
+        path.to_string()
+    }
+}
+
+// Directory of the local sentence embedding model, if configured
+pub fn get_embedding_path() -> Option<String> {
+    get_config(None).embedding_path.as_ref().map(|p| expand_home(p))
+}
+
+// Directory of the local question answering model, if configured
+pub fn get_qa_path() -> Option<String> {
+    get_config(None).qa_path.as_ref().map(|p| expand_home(p))
+}
+
+// The nnsplit sentence splitting model.onnx, if configured
+pub fn get_splitter_path() -> Option<String> {
+    get_config(None).splitter_path.as_ref().map(|p| expand_home(p))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_home_only_for_tilde_and_tilde_slash() {
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(expand_home("~"), home.to_str().unwrap());
+        assert_eq!(expand_home("~/notes"), home.join("notes").to_str().unwrap());
+        assert_eq!(expand_home("~bob/notes"), "~bob/notes");
+        assert_eq!(expand_home("/data/~/notes"), "/data/~/notes");
+        assert_eq!(expand_home(""), "");
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright: (c) 2023 Sureshkumar T
+// License: Apache-2.0
+
+// Interactive querying with `semdesk-cli shell`.  Lines are sent to the daemon
+// as queries on a single connection, and lines starting with a colon change
+// the options of the following queries or act on the last results.
+
+use std::error::Error;
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use crate::opener;
+use crate::protocol;
+use crate::settings;
+use crate::{absolute_path, breadcrumbs, Connection, DaemonError};
+
+const HELP: &str = "\
+Type a question to search, or one of:
+  :top N            retrieve N passages per query
+  :mode MODE        search semantic, lexical or hybrid
+  :filter path=DIR  only search files below DIR, :filter alone to clear
+  :open N           open result N at the matching line or page
+  :more N           show more of the document around result N
+  :options          show the current options
+  :help             show this help
+  :quit             leave the shell";
+
+// An answer from the last query and how much of its document to show around it
+struct Answer {
+    result: protocol::SearchResult,
+    context: usize,
+}
+
+struct Shell {
+    connection: Option<Connection>,
+    options: protocol::QueryOptions,
+    answers: Vec<Answer>,
+}
+
+impl Shell {
+    fn new() -> Self {
+        Shell { connection: None, options: protocol::QueryOptions::default(), answers: Vec::new() }
+    }
+
+    // Sends the request on the open connection.  The daemon closes idle
+    // connections, so a request that failed before any response came back is
+    // retried once on a fresh one.
+    fn request<F: FnMut(protocol::ResponseBody)>(&mut self, body: protocol::RequestBody, mut handle: F) -> Result<(), Box<dyn Error>> {
+        for attempt in 0..2 {
+            if self.connection.is_none() {
+                self.connection = Some(Connection::open()?);
+            }
+            // once responses were handled, a retry would handle them twice
+            let mut received = false;
+            let result = self.connection.as_mut().unwrap().request(body.clone(), |body| {
+                received = true;
+                handle(body)
+            });
+            match result {
+                Err(e) if !e.is::<DaemonError>() => {
+                    self.connection = None;
+                    if attempt == 1 || received {
+                        return Err(e);
+                    }
+                    tracing::debug!("Reconnecting: {}", e);
+                }
+                result => return result,
+            }
+        }
+        Ok(())
+    }
+
+    fn query(&mut self, query: &str) -> Result<(), Box<dyn Error>> {
+        let mut answers = Vec::new();
+        let body = protocol::RequestBody::Query { query: query.to_string(), options: self.options.clone() };
+        self.request(body, |body| {
+            match body {
+                protocol::ResponseBody::Hits { hits } => println!("Found {} passages", hits.len()),
+                protocol::ResponseBody::Results { results } => {
+                    for result in results {
+                        let page = result.page.map(|p| format!(" page {}", p)).unwrap_or_default();
+                        println!("\n[{}] {}{} ({:.2}%)", answers.len() + 1, breadcrumbs(&result.file, &result.section), page, result.score*100.0);
+                        println!("    {}", result.answer);
+                        answers.push(Answer { result, context: 0 });
+                    }
+                }
+                _ => {}
+            }
+        })?;
+        if answers.len() == 0 {
+            println!("No answers found");
+        }
+        // so `semdesk-cli open` works on them too
+        opener::save_results(&answers.iter().map(|a| a.result.clone()).collect::<Vec<_>>());
+        self.answers = answers;
+        Ok(())
+    }
+
+    fn answer(&mut self, arg: &str) -> Result<&mut Answer, Box<dyn Error>> {
+        let count = self.answers.len();
+        match arg.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= count => Ok(&mut self.answers[n - 1]),
+            _ => Err(format!("no result {}, the last query had {}", arg, count).into()),
+        }
+    }
+
+    fn open(&mut self, arg: &str) -> Result<(), Box<dyn Error>> {
+        opener::open(&self.answer(arg)?.result)
+    }
+
+    // Prints the chunk of the answer with one more chunk of the document on
+    // either side than the last time
+    fn more(&mut self, arg: &str) -> Result<(), Box<dyn Error>> {
+        let answer = self.answer(arg)?;
+        answer.context += 1;
+        let (file, chunk_id, context) = (answer.result.file.clone(), answer.result.chunk_id, answer.context);
+        let mut chunks = Vec::new();
+        self.request(protocol::RequestBody::Show { path: file }, |body| {
+            if let protocol::ResponseBody::File { chunks: c, .. } = body {
+                chunks = c;
+            }
+        })?;
+        let at = match chunks.iter().position(|c| c.id == chunk_id) {
+            Some(at) => at,
+            None => return Err("the document changed since the query, search again".into()),
+        };
+        let from = at.saturating_sub(context);
+        let to = (at + context + 1).min(chunks.len());
+        for (i, c) in chunks[from..to].iter().enumerate() {
+            let marker = if from + i == at { ">" } else { " " };
+            let page = c.page.map(|p| format!(" page {}", p)).unwrap_or_default();
+            println!("\n{} bytes {}..{}{}", marker, c.start, c.end, page);
+            if c.section.len() > 0 {
+                println!("  {}", c.section.join(" › "));
+            }
+            println!("{}", c.text);
+        }
+        Ok(())
+    }
+
+    fn print_options(&self) {
+        println!("top:    {}", self.options.top_k.map(|k| k.to_string()).unwrap_or(String::from("default")));
+        println!("mode:   {}", self.options.mode.clone().unwrap_or(String::from("default")));
+        println!("filter: {}", self.options.filters.path_prefix.clone().map(|p| format!("path={}", p)).unwrap_or(String::from("none")));
+    }
+
+    // Runs a line starting with a colon.  Returns false to leave the shell.
+    fn command(&mut self, line: &str) -> Result<bool, Box<dyn Error>> {
+        let (command, arg) = match line.split_once(char::is_whitespace) {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (line, ""),
+        };
+        match command {
+            ":top" => match arg.parse::<usize>() {
+                Ok(k) if k > 0 => self.options.top_k = Some(k),
+                _ => return Err(format!("expected a number of passages, not '{}'", arg).into()),
+            },
+            ":mode" => match arg {
+                "semantic" | "lexical" | "hybrid" => self.options.mode = Some(arg.to_string()),
+                _ => return Err(format!("unknown search mode '{}'", arg).into()),
+            },
+            ":filter" => match arg.split_once('=') {
+                Some(("path", path)) => self.options.filters.path_prefix = Some(absolute_path(path.trim())),
+                None if arg.len() == 0 => self.options.filters.path_prefix = None,
+                _ => return Err(format!("expected path=DIR, not '{}'", arg).into()),
+            },
+            ":open" => self.open(arg)?,
+            ":more" => self.more(arg)?,
+            ":options" => self.print_options(),
+            ":help" => println!("{}", HELP),
+            ":quit" | ":q" | ":exit" => return Ok(false),
+            _ => return Err(format!("unknown command {}, :help lists them", command).into()),
+        }
+        Ok(true)
+    }
+}
+
+pub fn run() -> Result<(), Box<dyn Error>> {
+    let mut shell = Shell::new();
+    // connect now so a daemon that isn't running is noticed before any typing
+    shell.connection = Some(Connection::open()?);
+
+    let mut editor = DefaultEditor::new()?;
+    let history = settings::get_shell_history_file();
+    let _ = editor.load_history(&history);
+    println!("Connected to semdesk.  Type :help for commands, :quit or Ctrl-D to leave.");
+    loop {
+        let line = match editor.readline("semdesk> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(Box::new(e)),
+        };
+        let line = line.trim();
+        if line.len() == 0 {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+        let handled = if line.starts_with(':') {
+            shell.command(line)
+        } else {
+            shell.query(line).map(|_| true)
+        };
+        match handled {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+    if let Err(e) = editor.save_history(&history) {
+        tracing::warn!("Could not save shell history to {}: {}", history, e);
+    }
+    Ok(())
+}